@@ -0,0 +1,24 @@
+// Updates the status of the invoice in place, from the websocket at `STATUS_URL`
+(function () {
+    var url = (location.protocol === "https:" ? "wss://" : "ws://") + location.host + STATUS_URL;
+    var done = false;
+
+    function connect() {
+        var socket = new WebSocket(url);
+        socket.onmessage = function (event) {
+            var status = JSON.parse(event.data);
+            document.querySelector("#status span").textContent = status.summary;
+            if (status.paid) {
+                document.getElementById("paid").style.display = "block";
+            }
+            done = status.done;
+        };
+        socket.onclose = function () {
+            if (!done) {
+                setTimeout(connect, 10000);
+            }
+        };
+    }
+
+    connect();
+})();
@@ -0,0 +1,616 @@
+use crate::{Error, Payment};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Returns the current unix time in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns a new random invoice id
+pub fn new_id() -> String {
+    random_string::generate(16, "abcdefghijklmnopqrstuvwxyz0123456789")
+}
+
+/// Lifecycle of an [`Invoice`](crate::invoice::Invoice)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvoiceState {
+    /// Waiting for a payment
+    New,
+    /// Received less than the requested amount
+    #[serde(alias = "processing")]
+    Underpaid,
+    /// Paid by transactions still in the mempool
+    Mempool,
+    /// Paid by transactions waiting for the required confirmations
+    Confirming,
+    /// Paid the requested amount, within the tolerance
+    #[serde(alias = "settled")]
+    Paid,
+    /// Paid more than the requested amount, above the tolerance
+    Overpaid,
+    /// Expired without receiving any payment
+    Expired,
+    /// Expired while underpaid
+    Invalid,
+}
+
+impl InvoiceState {
+    /// Returns true if the invoice is still waiting for payments
+    pub fn is_pending(&self) -> bool {
+        matches!(
+            self,
+            InvoiceState::New
+                | InvoiceState::Underpaid
+                | InvoiceState::Mempool
+                | InvoiceState::Confirming
+        )
+    }
+
+    /// Returns true if the requested amount has been received
+    pub fn is_paid(&self) -> bool {
+        matches!(self, InvoiceState::Paid | InvoiceState::Overpaid)
+    }
+}
+
+impl fmt::Display for InvoiceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            InvoiceState::New => "new",
+            InvoiceState::Underpaid => "underpaid",
+            InvoiceState::Mempool => "mempool",
+            InvoiceState::Confirming => "confirming",
+            InvoiceState::Paid => "paid",
+            InvoiceState::Overpaid => "overpaid",
+            InvoiceState::Expired => "expired",
+            InvoiceState::Invalid => "invalid",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+/// Price of an invoice in a fiat currency, converted at the rate locked when it was issued
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FiatPrice {
+    /// ISO 4217 code, e.g. `EUR`
+    pub currency: String,
+    /// Requested amount in `currency`
+    pub amount: f64,
+    /// Price of a bitcoin in `currency`
+    pub rate: f64,
+}
+
+impl FiatPrice {
+    /// Amount in sats at the locked rate, rounded up
+    pub fn sats(&self) -> u64 {
+        (self.amount / self.rate * 100_000_000.0).ceil() as u64
+    }
+}
+
+/// Payment request issued by a backend
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Invoice {
+    pub id: String,
+    /// Schema of the backend which issued the invoice, e.g. `bitcoin`
+    pub backend: String,
+    /// On-chain address or bolt11 invoice
+    pub address: String,
+    /// Bolt11 invoice offered next to the on-chain address, paying either settles the invoice
+    #[serde(default)]
+    pub lightning: Option<String>,
+    /// Requested amount in sats, any amount if missing
+    pub amount: Option<u64>,
+    pub currency: String,
+    /// Fiat price the amount was converted from, if requested in fiat
+    #[serde(default)]
+    pub fiat: Option<FiatPrice>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+    /// Creation unix time in seconds
+    pub created_at: u64,
+    /// Expiration unix time in seconds
+    pub expires_at: Option<u64>,
+    pub state: InvoiceState,
+    /// Amount received so far in sats
+    pub received: u64,
+    /// Confirmations of the least confirmed payment, if any
+    #[serde(default)]
+    pub confirmations: Option<u32>,
+    /// Confirmations needed to settle the invoice, 0 to accept payments in the mempool
+    #[serde(default = "default_required_confirmations")]
+    pub required_confirmations: u32,
+    /// Never settle with unconfirmed transactions signalling replace-by-fee
+    #[serde(default)]
+    pub refuse_rbf: bool,
+    /// Difference in sats from the requested amount still considered as paid
+    #[serde(default)]
+    pub tolerance: u64,
+}
+
+fn default_required_confirmations() -> u32 {
+    1
+}
+
+impl Invoice {
+    pub fn new(
+        backend: &str,
+        address: String,
+        currency: &str,
+        amount: Option<u64>,
+        label: Option<String>,
+        message: Option<String>,
+        expiry: Option<u64>,
+    ) -> Self {
+        let created_at = now();
+        Invoice {
+            id: new_id(),
+            backend: backend.to_string(),
+            address,
+            lightning: None,
+            amount,
+            currency: currency.to_string(),
+            fiat: None,
+            label,
+            message,
+            created_at,
+            expires_at: expiry.map(|expiry| created_at + expiry),
+            state: InvoiceState::New,
+            received: 0,
+            confirmations: None,
+            required_confirmations: default_required_confirmations(),
+            refuse_rbf: false,
+            tolerance: 0,
+        }
+    }
+
+    /// Returns true if the invoice has no amount, label or message
+    pub fn is_anonymous(&self) -> bool {
+        self.amount.is_none() && self.label.is_none() && self.message.is_none()
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if now >= expires_at)
+    }
+
+    /// Returns true if `payment` is final enough to settle the invoice
+    fn is_final(&self, payment: &Payment) -> bool {
+        match payment.confirmations {
+            // off-chain payments are final once received
+            None => true,
+            Some(0) if self.refuse_rbf && payment.rbf => false,
+            Some(confirmations) => confirmations >= self.required_confirmations,
+        }
+    }
+
+    /// Updates the state with the `payments` received so far,
+    /// returns true if the state changed.
+    pub fn update(&mut self, payments: &[Payment], now: u64) -> bool {
+        if !self.state.is_pending() {
+            return false;
+        }
+        // the payments to a reusable payment code, e.g. a bolt12 offer, count for the invoices
+        // issued for it by then
+        let payments: Vec<&Payment> = payments
+            .iter()
+            .filter(
+                |payment| !matches!(payment.paid_at, Some(paid_at) if paid_at < self.created_at),
+            )
+            .collect();
+        let received: u64 = payments.iter().map(|payment| payment.amount).sum();
+        let settled: u64 = payments
+            .iter()
+            .filter(|payment| self.is_final(payment))
+            .map(|payment| payment.amount)
+            .sum();
+        let confirmations = payments
+            .iter()
+            .filter_map(|payment| payment.confirmations)
+            .min();
+        let expired = self.is_expired(now);
+        let (min, max) = match self.amount {
+            Some(amount) => (
+                amount.saturating_sub(self.tolerance),
+                amount.saturating_add(self.tolerance),
+            ),
+            None => (1, u64::MAX),
+        };
+        let state = match received {
+            0 if expired => InvoiceState::Expired,
+            0 => InvoiceState::New,
+            _ if received < min && expired => InvoiceState::Invalid,
+            _ if received < min => InvoiceState::Underpaid,
+            // paid in time, waiting for the confirmations even after the expiry
+            _ if settled < min && confirmations == Some(0) => InvoiceState::Mempool,
+            _ if settled < min => InvoiceState::Confirming,
+            _ if received > max => InvoiceState::Overpaid,
+            _ => InvoiceState::Paid,
+        };
+        let changed =
+            state != self.state || received != self.received || confirmations != self.confirmations;
+        self.state = state;
+        self.received = received;
+        self.confirmations = confirmations;
+        changed
+    }
+
+    /// Returns a human readable status, e.g. `confirming (1/6)`
+    pub fn status(&self) -> String {
+        match self.state {
+            InvoiceState::Mempool => "seen in mempool".to_string(),
+            InvoiceState::Confirming => format!(
+                "confirming ({}/{})",
+                self.confirmations.unwrap_or(0),
+                self.required_confirmations
+            ),
+            state => state.to_string(),
+        }
+    }
+}
+
+/// Invoices persisted in a sled tree of the backend database, indexed by address and by
+/// pending state so that the lookups never scan every invoice ever issued
+#[derive(Clone)]
+pub struct InvoiceStore {
+    tree: sled::Tree,
+    /// Keys `<address>\0<id>` of every invoice
+    addresses: sled::Tree,
+    /// Ids of the invoices still waiting for payments
+    pending: sled::Tree,
+}
+
+/// Key of the invoice `id` in the address index
+fn address_key(address: &str, id: &str) -> Vec<u8> {
+    [address.as_bytes(), b"\0", id.as_bytes()].concat()
+}
+
+/// Sorts `invoices` oldest first, the ones issued in the same second by id
+fn sort(invoices: &mut [Invoice]) {
+    invoices.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+}
+
+impl InvoiceStore {
+    /// Opens the invoices tree of `wallet` in `db`, indexing the invoices stored before the
+    /// indexes existed
+    pub fn open(db: &sled::Db, wallet: &str) -> Result<Self, Error> {
+        let store = InvoiceStore {
+            tree: db.open_tree(format!("{}-invoices", wallet))?,
+            addresses: db.open_tree(format!("{}-addresses", wallet))?,
+            pending: db.open_tree(format!("{}-pending", wallet))?,
+        };
+        if store.addresses.is_empty() && !store.tree.is_empty() {
+            for invoice in store.list()? {
+                store.index(&invoice)?;
+            }
+            store.addresses.flush()?;
+            store.pending.flush()?;
+        }
+        Ok(store)
+    }
+
+    fn index(&self, invoice: &Invoice) -> Result<(), Error> {
+        self.addresses
+            .insert(address_key(&invoice.address, &invoice.id), &[])?;
+        match invoice.state.is_pending() {
+            true => self.pending.insert(invoice.id.as_bytes(), &[])?,
+            false => self.pending.remove(invoice.id.as_bytes())?,
+        };
+        Ok(())
+    }
+
+    pub fn insert(&self, invoice: &Invoice) -> Result<(), Error> {
+        let value = serde_json::to_vec(invoice)?;
+        self.index(invoice)?;
+        self.tree.insert(invoice.id.as_bytes(), value)?;
+        self.addresses.flush()?;
+        self.pending.flush()?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Invoice>, Error> {
+        match self.tree.get(id.as_bytes())? {
+            None => Ok(None),
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+        }
+    }
+
+    /// Returns the invoices of the indexed `ids`, oldest first
+    fn collect<I>(&self, ids: I) -> Result<Vec<Invoice>, Error>
+    where
+        I: Iterator<Item = sled::Result<sled::IVec>>,
+    {
+        let mut invoices = vec![];
+        for id in ids {
+            if let Some(value) = self.tree.get(id?)? {
+                invoices.push(serde_json::from_slice::<Invoice>(&value)?);
+            }
+        }
+        sort(&mut invoices);
+        Ok(invoices)
+    }
+
+    /// Returns all the invoices, oldest first
+    pub fn list(&self) -> Result<Vec<Invoice>, Error> {
+        let mut invoices = vec![];
+        for item in self.tree.iter() {
+            let (_, value) = item?;
+            invoices.push(serde_json::from_slice::<Invoice>(&value)?);
+        }
+        sort(&mut invoices);
+        Ok(invoices)
+    }
+
+    /// Returns the invoices still waiting for payments, oldest first
+    pub fn pending(&self) -> Result<Vec<Invoice>, Error> {
+        Ok(self
+            .collect(self.pending.iter().keys())?
+            .into_iter()
+            .filter(|invoice| invoice.state.is_pending())
+            .collect())
+    }
+
+    /// Returns the invoices issued for `address`, oldest first
+    pub fn for_address(&self, address: &str) -> Result<Vec<Invoice>, Error> {
+        let prefix = address_key(address, "");
+        let ids = self
+            .addresses
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|key| key.map(|key| sled::IVec::from(&key[prefix.len()..])));
+        self.collect(ids)
+    }
+
+    /// Returns the latest invoice issued for `address`
+    pub fn find_by_address(&self, address: &str) -> Result<Option<Invoice>, Error> {
+        Ok(self.for_address(address)?.pop())
+    }
+
+    /// Keeps the `payments` of `invoice` which count for it: a payment to a reusable payment
+    /// code, e.g. a bolt12 offer, only counts for the last invoice issued for it by then, so
+    /// that it is never credited to several invoices
+    pub fn attribute(
+        &self,
+        invoice: &Invoice,
+        payments: Vec<Payment>,
+    ) -> Result<Vec<Payment>, Error> {
+        if payments.iter().all(|payment| payment.paid_at.is_none()) {
+            return Ok(payments);
+        }
+        let invoices = self.for_address(&invoice.address)?;
+        Ok(payments
+            .into_iter()
+            .filter(|payment| match payment.paid_at {
+                Some(paid_at) => matches!(
+                    invoices.iter().rev().find(|issued| issued.created_at <= paid_at),
+                    Some(issued) if issued.id == invoice.id
+                ),
+                None => true,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::invoice::{FiatPrice, Invoice, InvoiceState, InvoiceStore};
+    use crate::Payment;
+
+    fn invoice(amount: Option<u64>) -> Invoice {
+        let mut invoice = Invoice::new(
+            "bitcoin",
+            "2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK".to_string(),
+            "BTC",
+            amount,
+            None,
+            None,
+            Some(3600),
+        );
+        invoice.created_at = 1000;
+        invoice.expires_at = Some(4600);
+        invoice
+    }
+
+    fn payment(amount: u64, confirmations: u32) -> Payment {
+        Payment {
+            txid: Some(
+                "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16".to_string(),
+            ),
+            payment_hash: None,
+            amount,
+            confirmations: Some(confirmations),
+            rbf: false,
+            paid_at: None,
+        }
+    }
+
+    #[test]
+    fn update_without_amount() {
+        let mut invoice = invoice(None);
+        assert!(!invoice.update(&[], 2000));
+        assert_eq!(InvoiceState::New, invoice.state);
+        assert!(invoice.update(&[payment(1, 1)], 2000));
+        assert_eq!(InvoiceState::Paid, invoice.state);
+        assert!(!invoice.update(&[payment(2, 1)], 2000));
+        assert_eq!(1, invoice.received);
+    }
+
+    #[test]
+    fn update_reused_address() {
+        let mut invoice = invoice(None);
+        let earlier = Payment {
+            paid_at: Some(999),
+            ..payment(1, 1)
+        };
+        assert!(!invoice.update(std::slice::from_ref(&earlier), 2000));
+        assert_eq!(InvoiceState::New, invoice.state);
+        let later = Payment {
+            paid_at: Some(1000),
+            ..payment(2, 1)
+        };
+        assert!(invoice.update(&[earlier, later], 2000));
+        assert_eq!(InvoiceState::Paid, invoice.state);
+        assert_eq!(2, invoice.received);
+    }
+
+    #[test]
+    fn update_with_amount() {
+        let mut invoice = invoice(Some(1000));
+        assert!(invoice.update(&[payment(400, 1)], 2000));
+        assert_eq!(InvoiceState::Underpaid, invoice.state);
+        assert!(invoice.update(&[payment(400, 2), payment(600, 1)], 2000));
+        assert_eq!(InvoiceState::Paid, invoice.state);
+    }
+
+    #[test]
+    fn update_expired() {
+        let mut invoice = invoice(Some(1000));
+        assert!(invoice.update(&[], 5000));
+        assert_eq!(InvoiceState::Expired, invoice.state);
+
+        let mut invoice = self::invoice(Some(1000));
+        assert!(invoice.update(&[payment(400, 1)], 5000));
+        assert_eq!(InvoiceState::Invalid, invoice.state);
+    }
+
+    #[test]
+    fn update_confirmations() {
+        let mut invoice = invoice(Some(1000));
+        invoice.required_confirmations = 6;
+        assert!(invoice.update(&[payment(1000, 0)], 2000));
+        assert_eq!(InvoiceState::Mempool, invoice.state);
+        assert_eq!("seen in mempool", invoice.status());
+        // still waiting for the confirmations after the expiry
+        assert!(invoice.update(&[payment(1000, 2)], 5000));
+        assert_eq!(InvoiceState::Confirming, invoice.state);
+        assert_eq!("confirming (2/6)", invoice.status());
+        assert!(invoice.update(&[payment(1000, 6)], 5000));
+        assert_eq!(InvoiceState::Paid, invoice.state);
+    }
+
+    #[test]
+    fn update_tolerance() {
+        let mut invoice = invoice(Some(1000));
+        invoice.tolerance = 10;
+        assert!(invoice.update(&[payment(989, 1)], 2000));
+        assert_eq!(InvoiceState::Underpaid, invoice.state);
+        assert!(invoice.update(&[payment(989, 1), payment(1, 1)], 2000));
+        assert_eq!(InvoiceState::Paid, invoice.state);
+
+        let mut invoice = self::invoice(Some(1000));
+        invoice.tolerance = 10;
+        assert!(invoice.update(&[payment(1010, 1)], 2000));
+        assert_eq!(InvoiceState::Paid, invoice.state);
+
+        let mut invoice = self::invoice(Some(1000));
+        invoice.tolerance = 10;
+        assert!(invoice.update(&[payment(500, 1), payment(511, 0)], 2000));
+        assert_eq!(InvoiceState::Mempool, invoice.state);
+        assert!(invoice.update(&[payment(500, 2), payment(511, 1)], 2000));
+        assert_eq!(InvoiceState::Overpaid, invoice.state);
+        assert_eq!(1011, invoice.received);
+    }
+
+    #[test]
+    fn update_zero_conf() {
+        let mut rbf = payment(1000, 0);
+        rbf.rbf = true;
+
+        let mut invoice = invoice(Some(1000));
+        invoice.required_confirmations = 0;
+        assert!(invoice.update(&[rbf.clone()], 2000));
+        assert_eq!(InvoiceState::Paid, invoice.state);
+
+        let mut invoice = self::invoice(Some(1000));
+        invoice.required_confirmations = 0;
+        invoice.refuse_rbf = true;
+        assert!(invoice.update(&[rbf], 2000));
+        assert_eq!(InvoiceState::Mempool, invoice.state);
+        assert!(invoice.update(&[payment(1000, 1)], 2000));
+        assert_eq!(InvoiceState::Paid, invoice.state);
+    }
+
+    #[test]
+    fn fiat_sats() {
+        let price = |amount: f64, rate: f64| FiatPrice {
+            currency: "EUR".to_string(),
+            amount,
+            rate,
+        };
+        assert_eq!(10_000, price(2.5, 25_000.0).sats());
+        // rounded up, the payer never pays less than the price
+        assert_eq!(13_718, price(4.5, 32_805.0).sats());
+        assert_eq!(100_000_000, price(30_000.0, 30_000.0).sats());
+    }
+
+    #[test]
+    fn attribute_reused_address() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = InvoiceStore::open(&db, "test").unwrap();
+        let mut first = invoice(None);
+        first.id = "a".to_string();
+        let mut second = invoice(None);
+        second.id = "b".to_string();
+        second.created_at = 2000;
+        store.insert(&first).unwrap();
+        store.insert(&second).unwrap();
+
+        let paid_at = |paid_at| Payment {
+            paid_at: Some(paid_at),
+            ..payment(1, 1)
+        };
+        let payments = vec![paid_at(999), paid_at(1500), paid_at(2000), payment(2, 1)];
+        assert_eq!(
+            vec![paid_at(1500), payment(2, 1)],
+            store.attribute(&first, payments.clone()).unwrap()
+        );
+        assert_eq!(
+            vec![paid_at(2000), payment(2, 1)],
+            store.attribute(&second, payments).unwrap()
+        );
+    }
+
+    #[test]
+    fn store_indexes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = InvoiceStore::open(&db, "test").unwrap();
+        let mut first = invoice(None);
+        first.id = "a".to_string();
+        let mut second = invoice(Some(1000));
+        second.id = "b".to_string();
+        second.created_at = 2000;
+        let mut other = invoice(None);
+        other.id = "c".to_string();
+        other.address = "2N".to_string();
+        for invoice in [&first, &second, &other].iter() {
+            store.insert(invoice).unwrap();
+        }
+        let address = first.address.clone();
+        assert_eq!(
+            Some(second.clone()),
+            store.find_by_address(&address).unwrap()
+        );
+        assert_eq!(
+            vec![first.clone(), second.clone()],
+            store.for_address(&address).unwrap()
+        );
+        // not a prefix match of the longer address
+        assert_eq!(None, store.find_by_address("2").unwrap());
+        assert_eq!(3, store.pending().unwrap().len());
+
+        second.state = InvoiceState::Paid;
+        store.insert(&second).unwrap();
+        assert_eq!(vec![first.clone(), other.clone()], store.pending().unwrap());
+
+        // indexes rebuilt for the invoices stored without them
+        db.drop_tree("test-addresses").unwrap();
+        db.drop_tree("test-pending").unwrap();
+        let store = InvoiceStore::open(&db, "test").unwrap();
+        assert_eq!(Some(second), store.find_by_address(&address).unwrap());
+        assert_eq!(vec![first, other], store.pending().unwrap());
+    }
+}
@@ -0,0 +1,220 @@
+pub mod invoice;
+
+pub extern crate sled;
+
+use invoice::InvoiceStore;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A payment received by an address (or invoice)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payment {
+    /// Id of the on-chain transaction
+    pub txid: Option<String>,
+    /// Payment hash of the lightning invoice
+    pub payment_hash: Option<String>,
+    /// Amount in sats
+    pub amount: u64,
+    /// Confirmations of the transaction, if known
+    pub confirmations: Option<u32>,
+    /// True if the transaction signals replace-by-fee (BIP125)
+    pub rbf: bool,
+    /// Unix time of the payments to a reusable payment code, e.g. a bolt12 offer, which only
+    /// count for the last invoice issued with it by then, see
+    /// [`InvoiceStore::attribute`](crate::invoice::InvoiceStore::attribute)
+    pub paid_at: Option<u64>,
+}
+
+/// Details of the invoice an address is requested for, embedded in it by the backends which
+/// can, e.g. in a bolt11
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InvoiceParams {
+    /// Id of the [`Invoice`](crate::invoice::Invoice), to look the payment up on the backend
+    pub id: String,
+    /// Requested amount in millisatoshis, any amount if missing
+    pub amount_msat: Option<u64>,
+    /// Description shown to the payer
+    pub description: Option<String>,
+    /// Commit to the hash of the description only, leaving it out of the invoice, e.g. for LNURL
+    pub description_hash_only: bool,
+    /// Seconds the invoice can be paid for
+    pub expiry: Option<u64>,
+    /// On-chain address the payer can fall back to
+    pub fallback: Option<String>,
+}
+
+/// Something observed by a backend since the last poll
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    /// A new block at the given height
+    NewBlock(u32),
+    /// New payments to a watched address, or to a lightning invoice
+    Activity(String),
+}
+
+/// Underlying error of an [`Error`], a plain message converts into it with `into()`
+pub type Cause = Box<dyn std::error::Error + Send + Sync>;
+
+/// Errors that can be thrown by a [`PaymentBackend`](crate::PaymentBackend), keeping their
+/// cause as [`source`](std::error::Error::source)
+#[derive(Debug)]
+pub enum Error {
+    /// The backend is misconfigured, e.g. with a malformed descriptor
+    Config(Cause),
+    /// The address is malformed or belongs to another network
+    InvalidAddress(Cause),
+    /// The node or server behind the backend is unreachable
+    Connection(Cause),
+    /// The backend failed to serve the request
+    Backend(Cause),
+    /// The invoice database failed
+    Database(Cause),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(e) => write!(f, "invalid configuration: {}", e),
+            Error::InvalidAddress(e) => write!(f, "invalid address: {}", e),
+            Error::Connection(e) => write!(f, "connection failed: {}", e),
+            Error::Backend(e) => write!(f, "backend failed: {}", e),
+            Error::Database(e) => write!(f, "database failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Config(e)
+            | Error::InvalidAddress(e)
+            | Error::Connection(e)
+            | Error::Backend(e)
+            | Error::Database(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Database(Box::new(e))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Database(Box::new(e))
+    }
+}
+
+/// Brings the state of a backend up to date apart from it, so that the requests to the backend
+/// are served while syncing, see [`PaymentBackend::syncer`]
+pub trait Syncer: Send {
+    fn sync(&mut self) -> Result<(), Error>;
+}
+
+/// A payment rail the server can collect funds with.
+///
+/// Every backend crate (bitcoin, liquid, lightning) implements this trait, and the server only
+/// deals with `Box<dyn PaymentBackend>`, so new backends can be plugged in without touching it.
+pub trait PaymentBackend: Send {
+    /// Returns an address (or invoice) ready to be paid, for the invoice without an amount
+    /// described by `params`.
+    ///
+    /// Backends issuing an invoice per address, e.g. a bolt11, label it with `params.id`.
+    fn last_unused_address(&mut self, params: &InvoiceParams) -> Result<String, Error>;
+
+    /// Returns an address (or invoice) never handed out before, for the invoice described by
+    /// `params`.
+    fn new_address(&mut self, params: &InvoiceParams) -> Result<String, Error> {
+        self.last_unused_address(params)
+    }
+
+    /// Returns a new address (or invoice) for the invoice described by `params`.
+    ///
+    /// On-chain backends hand out a plain address, the amount goes in the payment uri.
+    fn invoice_address(&mut self, params: &InvoiceParams) -> Result<String, Error> {
+        self.new_address(params)
+    }
+
+    /// Checks if `addr` has been issued by this backend.
+    fn is_my_address(&mut self, addr: &str) -> Result<bool, Error>;
+
+    /// Returns the amounts received by `addr`, keyed by asset.
+    fn balance_address(
+        &mut self,
+        addr: &str,
+        from_height: Option<usize>,
+    ) -> Result<HashMap<String, String>, Error>;
+
+    /// Returns the payments received by `addr`, by default a single one summing its balances.
+    fn payments(&mut self, addr: &str) -> Result<Vec<Payment>, Error> {
+        let amount: u64 = self
+            .balance_address(addr, Option::from(0))?
+            .values()
+            .filter_map(|value| value.parse::<u64>().ok())
+            .sum();
+        match amount {
+            0 => Ok(vec![]),
+            _ => Ok(vec![Payment {
+                txid: None,
+                payment_hash: None,
+                amount,
+                confirmations: None,
+                rbf: false,
+                paid_at: None,
+            }]),
+        }
+    }
+
+    /// Returns the amount in sats received by `addr`.
+    fn received(&mut self, addr: &str) -> Result<u64, Error> {
+        Ok(self
+            .payments(addr)?
+            .iter()
+            .map(|payment| payment.amount)
+            .sum())
+    }
+
+    /// Brings the backend state up to date, called periodically by the server monitor.
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Returns a [`Syncer`] sharing the backend state, called once by the server monitor, which
+    /// then syncs with it instead of [`sync`](PaymentBackend::sync) without locking the backend.
+    ///
+    /// Backends with a long sync, e.g. an electrum wallet, provide one.
+    fn syncer(&mut self) -> Result<Option<Box<dyn Syncer>>, Error> {
+        Ok(None)
+    }
+
+    /// Starts watching `addr`, reporting its payments as [`Notification::Activity`] on poll.
+    fn watch(&mut self, _addr: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Stops watching `addr`.
+    fn unwatch(&mut self, _addr: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Returns what happened since the last poll, called frequently by the server monitor.
+    fn poll(&mut self) -> Result<Vec<Notification>, Error> {
+        Ok(vec![])
+    }
+
+    /// Returns the network name shown to the payer.
+    fn network(&mut self) -> Result<String, Error>;
+
+    /// Returns the URI scheme of the payment links, e.g. `bitcoin`.
+    fn schema(&self) -> &str;
+
+    /// Returns the currency the invoices are denominated in.
+    fn currency(&self) -> &str {
+        "BTC"
+    }
+
+    /// Returns the store persisting the invoices issued by this backend.
+    fn invoices(&self) -> InvoiceStore;
+}
@@ -1,20 +1,70 @@
 use bdk::bitcoin::{Address, Amount, Denomination};
 use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 use std::collections::BTreeMap;
+use std::fmt;
 use std::str::FromStr;
 use url::{ParseError, Url};
 
+/// Parameters understood by [`Bip21`](crate::bip21::Bip21), any other one goes to the extras
+const PARAMS: [&str; 4] = ["amount", "label", "message", "lightning"];
+/// Prefix of the parameters a wallet must understand to pay the uri
+const REQ_PREFIX: &str = "req-";
+
+/// Errors parsing a [`Bip21`](crate::bip21::Bip21) uri
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bip21Error {
+    /// The uri is malformed
+    Uri(ParseError),
+    /// The address is malformed
+    Address(String),
+    /// The amount is not a number of bitcoins
+    Amount(String),
+    /// A `req-` parameter is not understood, the uri can not be paid
+    RequiredParam(String),
+}
+
+impl fmt::Display for Bip21Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bip21Error::Uri(e) => write!(f, "invalid uri: {}", e),
+            Bip21Error::Address(e) => write!(f, "invalid address: {}", e),
+            Bip21Error::Amount(e) => write!(f, "invalid amount: {}", e),
+            Bip21Error::RequiredParam(name) => write!(f, "unknown required parameter {}", name),
+        }
+    }
+}
+
+impl std::error::Error for Bip21Error {}
+
+impl From<ParseError> for Bip21Error {
+    fn from(e: ParseError) -> Self {
+        Bip21Error::Uri(e)
+    }
+}
+
 pub struct Bip21 {
     pub scheme: String,
     pub address: Address,
     pub amount: Option<Amount>,
     pub label: Option<String>,
     pub message: Option<String>,
+    /// BOLT11 invoice offered to pay the same amount over lightning
+    pub lightning: Option<String>,
+    /// Any other parameter, `req-` ones included
+    pub extras: BTreeMap<String, String>,
 }
 
 impl Bip21 {
     pub fn as_str(&self) -> Result<String, ParseError> {
-        const FRAGMENT: &AsciiSet = &CONTROLS.add(b' ');
+        // the query delimiters too, as labels and messages are free text
+        const FRAGMENT: &AsciiSet = &CONTROLS
+            .add(b' ')
+            .add(b'#')
+            .add(b'%')
+            .add(b'&')
+            .add(b'+')
+            .add(b'=')
+            .add(b'?');
         let mut query = BTreeMap::new();
         if let Some(a) = &self.amount {
             query.insert("amount", a.as_btc().to_string());
@@ -27,52 +77,80 @@ impl Bip21 {
             let encoded = utf8_percent_encode(m.as_str(), FRAGMENT).to_string();
             query.insert("message", encoded);
         }
+        if let Some(l) = &self.lightning {
+            query.insert("lightning", l.clone());
+        }
+        for (k, v) in self.extras.iter() {
+            let encoded = utf8_percent_encode(v.as_str(), FRAGMENT).to_string();
+            query.insert(k.as_str(), encoded);
+        }
         let params = query
             .into_iter()
             .map(|(k, v)| format!("{}={}", k, v))
             .collect::<Vec<String>>();
-        let url = format!(
-            "{}:{}?{}",
-            self.scheme,
-            self.address.to_string(),
-            params.join("&")
-        );
+        let url = format!("{}:{}?{}", self.scheme, self.address, params.join("&"));
         Ok(url.trim_end_matches("?").to_string())
     }
 
-    pub fn parse(string: &str) -> Result<Self, ParseError> {
+    /// Parses a uri, failing on any `req-` parameter.
+    pub fn parse(string: &str) -> Result<Self, Bip21Error> {
+        Self::parse_with(string, &[])
+    }
+
+    /// Parses a uri, failing on the `req-` parameters other than `required` (without prefix),
+    /// as they must be understood to pay it.
+    pub fn parse_with(string: &str, required: &[&str]) -> Result<Self, Bip21Error> {
         let url = Url::parse(string)?;
         let mut params: BTreeMap<String, String> = BTreeMap::new();
         for (k, v) in url.query_pairs().into_owned() {
             params.insert(k, v);
         }
         let scheme = url.scheme().to_string();
-        let address = Address::from_str(url.path()).map_err(|_| ParseError::IdnaError)?;
+        let address = Address::from_str(url.path())
+            .map_err(|e| Bip21Error::Address(format!("{}: {}", url.path(), e)))?;
         let amount = match params.get("amount") {
             None => None,
             Some(amount) => Some(
                 Amount::from_str_in(amount.as_str(), Denomination::Bitcoin)
-                    .map_err(|_| ParseError::IdnaError)?,
+                    .map_err(|e| Bip21Error::Amount(format!("{}: {}", amount, e)))?,
             ),
         };
         let label = params.get("label").cloned();
         let message = params.get("message").cloned();
+        let lightning = params.get("lightning").cloned();
+        let mut extras = BTreeMap::new();
+        for (k, v) in params.into_iter() {
+            if PARAMS.contains(&k.as_str()) {
+                continue;
+            }
+            if let Some(name) = k.strip_prefix(REQ_PREFIX) {
+                if !required.contains(&name) {
+                    return Err(Bip21Error::RequiredParam(k));
+                }
+            }
+            extras.insert(k, v);
+        }
         Ok(Bip21 {
             scheme,
             address,
             amount,
             label,
             message,
+            lightning,
+            extras,
         })
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::bip21::Bip21;
+    use crate::bip21::{Bip21, Bip21Error};
     use bdk::bitcoin::{Address, Amount, Denomination};
+    use std::collections::BTreeMap;
     use std::str::FromStr;
 
+    const BOLT11: &str = "lnbc20m1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqsfpp3qjmp7lwpagxun9pygexvgpjdc4jdj85fr9yq20q82gphp2nflc7jtzrcazrra7wwgzxqc8u7754cdlpfrmccae92qgzqvzq2ps8pqqqqqqpqqqqq9qqqvpeuqafqxu92d8lr6fvg0r5gv0heeeqgcrqlnm6jhphu9y00rrhy4grqszsvpcgpy9qqqqqqgqqqqq7qqzqj9n4evl6mr5aj9f58zp6fyjzup6ywn3x6sk8akg5v4tgn2q8g4fhx05wf6juaxu9760yp46454gpg5mtzgerlzezqcqvjnhjh8z3g2qqdhhwkj";
+
     #[test]
     fn serialize() {
         let mut bip21 = Bip21 {
@@ -81,6 +159,8 @@ mod test {
             amount: None,
             label: None,
             message: None,
+            lightning: None,
+            extras: BTreeMap::new(),
         };
         assert_eq!(
             "bitcoin:2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK",
@@ -102,6 +182,13 @@ mod test {
             "bitcoin:2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK?amount=50&label=Luke-Jr&message=Donation%20for%20project%20xyz",
             bip21.as_str().unwrap()
         );
+        bip21.message = Some("Tea & cake = 100%".to_string());
+        let uri = bip21.as_str().unwrap();
+        assert_eq!(
+            "bitcoin:2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK?amount=50&label=Luke-Jr&message=Tea%20%26%20cake%20%3D%20100%25",
+            uri
+        );
+        assert_eq!(bip21.message, Bip21::parse(&uri).unwrap().message);
     }
 
     #[test]
@@ -118,8 +205,66 @@ mod test {
         );
         let url2 = Bip21::parse("bitcoin:2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK?amount=50&label=Luke-Jr&message=Donation%20for%20project%20xyz").unwrap();
         assert_eq!("bitcoin:2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK?amount=50&label=Luke-Jr&message=Donation%20for%20project%20xyz", url2.as_str().unwrap());
-        assert_eq!(50 as f64, url2.amount.unwrap().as_btc());
+        assert_eq!(50.0, url2.amount.unwrap().as_btc());
         assert_eq!("Luke-Jr", url2.label.unwrap().as_str());
         assert_eq!("Donation for project xyz", url2.message.unwrap().as_str());
     }
+
+    #[test]
+    fn errors() {
+        let err = |uri: &str| Bip21::parse(uri).err().unwrap();
+        assert!(matches!(
+            err("2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK"),
+            Bip21Error::Uri(_)
+        ));
+        assert!(matches!(err("bitcoin:2NDxu"), Bip21Error::Address(_)));
+        assert!(matches!(
+            err("bitcoin:2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK?amount=lots"),
+            Bip21Error::Amount(_)
+        ));
+        assert!(
+            err("bitcoin:2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK?amount=lots")
+                .to_string()
+                .starts_with("invalid amount: lots")
+        );
+    }
+
+    #[test]
+    fn lightning() {
+        let uri = format!(
+            "bitcoin:2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK?amount=0.02&lightning={}",
+            BOLT11
+        );
+        let bip21 = Bip21::parse(&uri).unwrap();
+        assert_eq!(Some(BOLT11.to_string()), bip21.lightning);
+        assert!(bip21.extras.is_empty());
+        assert_eq!(uri, bip21.as_str().unwrap());
+    }
+
+    #[test]
+    fn extras() {
+        let bip21 = Bip21::parse(
+            "bitcoin:2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK?somethingyoudontunderstand=50&somethingelseyoudontget=999",
+        )
+        .unwrap();
+        assert_eq!(2, bip21.extras.len());
+        assert_eq!("50", bip21.extras["somethingyoudontunderstand"]);
+
+        // required parameters fail unless understood
+        let uri = "bitcoin:2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK?req-somethingelseyoudontget=999&req-somethingyoudontunderstand=50";
+        assert!(Bip21::parse(uri).is_err());
+        assert_eq!(
+            Some(Bip21Error::RequiredParam(
+                "req-somethingelseyoudontget".to_string()
+            )),
+            Bip21::parse_with(uri, &["somethingyoudontunderstand"]).err()
+        );
+        let bip21 = Bip21::parse_with(
+            uri,
+            &["somethingyoudontunderstand", "somethingelseyoudontget"],
+        )
+        .unwrap();
+        assert_eq!("999", bip21.extras["req-somethingelseyoudontget"]);
+        assert_eq!(uri, bip21.as_str().unwrap());
+    }
 }
@@ -48,6 +48,16 @@ pub struct BitcoinOpts {
         default_value = "btctipserver"
     )]
     pub wallet: String,
+    /// Offer a bolt11 invoice of the lightning node next to every address, in a unified BIP21
+    /// uri
+    #[structopt(
+        name = "LIGHTNING",
+        env = "BDK_LIGHTNING",
+        long = "lightning",
+        parse(try_from_str),
+        default_value = "false"
+    )]
+    pub lightning: bool,
     #[structopt(flatten)]
     pub electrum_opts: ElectrumOpts,
 }
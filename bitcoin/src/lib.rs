@@ -2,54 +2,173 @@ pub mod bip21;
 pub mod config;
 
 pub extern crate bdk;
+extern crate btctipserver_backend;
 extern crate percent_encoding;
 extern crate structopt;
 extern crate url;
 
-use bdk::bitcoin::Address;
+use bdk::bitcoin::util::address;
+use bdk::bitcoin::{Address, Network, Script, Transaction};
 use bdk::blockchain::{
     log_progress, AnyBlockchain, AnyBlockchainConfig, ConfigurableBlockchain,
     ElectrumBlockchainConfig,
 };
-use bdk::electrum_client::{Client, ElectrumApi, ListUnspentRes};
+use bdk::electrum_client::{self, Client, ElectrumApi, GetHistoryRes};
 use bdk::sled::{self, Tree};
-use bdk::wallet::AddressIndex::LastUnused;
+use bdk::wallet::AddressIndex::{LastUnused, New};
+use btctipserver_backend::invoice::InvoiceStore;
+use btctipserver_backend::Error as BackendError;
+use btctipserver_backend::{InvoiceParams, Notification, Payment, PaymentBackend, Syncer};
 use config::BitcoinOpts;
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread;
+use std::time::Duration;
 
-pub fn gen_err() -> bdk::Error {
-    bdk::Error::Generic(format!("oh no!"))
+/// How often the electrum server is pinged, so that its notifications are read
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+type Wallet = bdk::Wallet<AnyBlockchain, Tree>;
+
+/// Errors that can be thrown by the [`BTCWallet`](crate::BTCWallet)
+#[derive(Debug)]
+pub enum Error {
+    /// The bdk wallet failed, e.g. with an invalid descriptor
+    Wallet(bdk::Error),
+    /// The electrum server is unreachable or failed
+    Electrum(electrum_client::Error),
+    /// The address is malformed
+    Address(address::Error),
+    /// The address belongs to another network than the wallet one
+    Network(String, Network),
+    /// The datadir or the database are unusable
+    Database(String),
+    /// The invoice store failed
+    Backend(BackendError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Wallet(e) => write!(f, "wallet: {}", e),
+            Error::Electrum(e) => write!(f, "electrum: {}", e),
+            Error::Address(e) => write!(f, "{}", e),
+            Error::Network(address, network) => {
+                write!(f, "address {} is not for network {}", address, network)
+            }
+            Error::Database(e) => write!(f, "database: {}", e),
+            Error::Backend(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<bdk::Error> for Error {
+    fn from(e: bdk::Error) -> Self {
+        Error::Wallet(e)
+    }
+}
+
+impl From<electrum_client::Error> for Error {
+    fn from(e: electrum_client::Error) -> Self {
+        Error::Electrum(e)
+    }
+}
+
+impl From<address::Error> for Error {
+    fn from(e: address::Error) -> Self {
+        Error::Address(e)
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Database(e.to_string())
+    }
+}
+
+impl From<BackendError> for Error {
+    fn from(e: BackendError) -> Self {
+        Error::Backend(e)
+    }
+}
+
+impl From<Error> for BackendError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Wallet(bdk::Error::Electrum(e)) | Error::Electrum(e) => {
+                BackendError::Connection(Box::new(e))
+            }
+            Error::Wallet(e @ bdk::Error::Descriptor(_)) => BackendError::Config(Box::new(e)),
+            Error::Wallet(e) => BackendError::Backend(Box::new(e)),
+            Error::Address(e) => BackendError::InvalidAddress(Box::new(e)),
+            Error::Network(_, _) => BackendError::InvalidAddress(e.to_string().into()),
+            Error::Database(e) => BackendError::Database(e.into()),
+            Error::Backend(e) => e,
+        }
+    }
 }
 
 pub struct BTCWallet {
-    wallet: bdk::Wallet<AnyBlockchain, Tree>,
-    client: Client,
+    /// Wallet shared with the syncer, only one of them deriving addresses or syncing at a time
+    wallet: Arc<Mutex<Wallet>>,
+    conf: BitcoinOpts,
+    client: Arc<Client>,
+    /// Last failure of the pings of `client`, reported by the next poll
+    ping_error: Arc<Mutex<Option<electrum_client::Error>>>,
+    invoices: InvoiceStore,
+    /// Subscribed scripts and their address
+    watched: HashMap<Script, String>,
 }
 
 impl BTCWallet {
-    pub fn prepare_home_dir(datadir: &str) -> PathBuf {
+    pub fn prepare_home_dir(datadir: &str) -> Result<PathBuf, Error> {
         let mut dir = PathBuf::new();
-        dir.push(&dirs_next::home_dir().unwrap());
+        let home = dirs_next::home_dir()
+            .ok_or_else(|| Error::Database("no home directory".to_string()))?;
+        dir.push(&home);
         dir.push(datadir);
 
         if !dir.exists() {
             //info!("Creating home directory {}", dir.as_path().display());
-            fs::create_dir(&dir).unwrap();
+            fs::create_dir(&dir)
+                .map_err(|e| Error::Database(format!("{}: {}", dir.display(), e)))?;
         }
 
         dir.push("database.sled");
-        dir
+        Ok(dir)
     }
 
-    pub fn new(conf: &BitcoinOpts) -> Result<Self, bdk::Error> {
+    pub fn new(conf: &BitcoinOpts) -> Result<Self, Error> {
         // setup database
-        let database = sled::open(Self::prepare_home_dir(&conf.data_dir).to_str().unwrap())?;
+        let database = sled::open(Self::prepare_home_dir(&conf.data_dir)?)?;
         let tree = database.open_tree(&conf.wallet)?;
+        let invoices = InvoiceStore::open(&database, &conf.wallet)?;
+
+        // create wallet shared by all requests
+        let wallet = Self::open_wallet(conf, tree)?;
+        let client = Arc::new(Client::new(&conf.electrum_opts.electrum)?);
+        client.block_headers_subscribe()?;
+        wallet.sync(log_progress(), None)?;
+        let ping_error = Arc::new(Mutex::new(None));
+        ping(Arc::downgrade(&client), ping_error.clone());
+        Ok(BTCWallet {
+            wallet: Arc::new(Mutex::new(wallet)),
+            conf: conf.clone(),
+            client,
+            ping_error,
+            invoices,
+            watched: HashMap::new(),
+        })
+    }
 
-        // setup electrum blockchain client
+    /// Opens the wallet of `conf` on `tree`, with an electrum connection of its own
+    fn open_wallet(conf: &BitcoinOpts, tree: Tree) -> Result<Wallet, Error> {
         let electrum_opts = conf.electrum_opts.clone();
         let electrum_config = AnyBlockchainConfig::Electrum(ElectrumBlockchainConfig {
             url: electrum_opts.electrum,
@@ -58,80 +177,221 @@ impl BTCWallet {
             timeout: electrum_opts.timeout,
             stop_gap: 20,
         });
-
-        // create wallet shared by all requests
-        let wallet = bdk::Wallet::new(
+        Ok(bdk::Wallet::new(
             &conf.descriptor,
             None,
             conf.network,
             tree,
             AnyBlockchain::from_config(&electrum_config)?,
-        )?;
-        let client = Client::new(&conf.electrum_opts.electrum).unwrap();
-        wallet.sync(log_progress(), None)?;
-        Ok(BTCWallet { wallet, client })
+        )?)
     }
 
-    fn check_address(
-        &self,
-        addr: &str,
-        from_height: Option<usize>,
-    ) -> Result<Vec<ListUnspentRes>, bdk::Error> {
-        let monitor_script = Address::from_str(addr)
-            .map_err(|_| gen_err())?
-            .script_pubkey();
+    /// Locks the wallet, waiting for the sync of the syncer
+    fn wallet(&self) -> MutexGuard<'_, Wallet> {
+        self.wallet.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 
-        let unspents = self.client.script_list_unspent(&monitor_script)?;
+    /// Parses `addr`, checking it belongs to the network of the wallet
+    fn address(&self, addr: &str) -> Result<Address, Error> {
+        let address = Address::from_str(addr)?;
+        let network = self.conf.network;
+        // the test networks share their base58 prefixes, only mainnet can be told apart
+        if (address.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+            return Err(Error::Network(addr.to_string(), network));
+        }
+        Ok(address)
+    }
 
-        let array = unspents
+    /// Returns the payments received by `addr` in its whole history, spent outputs included,
+    /// skipping the transactions confirmed before `from_height`.
+    fn history(&self, addr: &str, from_height: Option<usize>) -> Result<Vec<Payment>, Error> {
+        let script = self.address(addr)?.script_pubkey();
+        let tip = self.client.block_headers_subscribe()?.height;
+        let history: Vec<GetHistoryRes> = self
+            .client
+            .script_get_history(&script)?
             .into_iter()
-            .filter(|x| x.height >= from_height.unwrap_or(0))
+            .filter(|item| item.height.max(0) as usize >= from_height.unwrap_or(0))
             .collect();
+        let txs = self
+            .client
+            .batch_transaction_get(history.iter().map(|item| &item.tx_hash))?;
+
+        let mut payments = vec![];
+        for (item, tx) in history.iter().zip(txs) {
+            let amount: u64 = tx
+                .output
+                .iter()
+                .filter(|output| output.script_pubkey == script)
+                .map(|output| output.value)
+                .sum();
+            // transactions spending from the address
+            if amount == 0 {
+                continue;
+            }
+            // unconfirmed transactions have height 0, or -1 with unconfirmed parents
+            let confirmations = match item.height {
+                height if height <= 0 => 0,
+                height => (tip + 1).saturating_sub(height as usize) as u32,
+            };
+            payments.push(Payment {
+                txid: Some(item.tx_hash.to_string()),
+                payment_hash: None,
+                amount,
+                confirmations: Some(confirmations),
+                // only unconfirmed transactions can be replaced
+                rbf: confirmations == 0 && signals_rbf(&tx),
+                paid_at: None,
+            });
+        }
+        Ok(payments)
+    }
+}
 
-        Ok(array)
+/// Pings the electrum server of `client` every [`PING_INTERVAL`] from a thread of its own,
+/// so that its notifications are read without locking the [`BTCWallet`], until it is dropped
+fn ping(weak: Weak<Client>, error: Arc<Mutex<Option<electrum_client::Error>>>) {
+    thread::spawn(move || {
+        while let Some(client) = weak.upgrade() {
+            if let Err(e) = client.ping() {
+                *error.lock().unwrap_or_else(PoisonError::into_inner) = Some(e);
+            }
+            drop(client);
+            thread::sleep(PING_INTERVAL);
+        }
+    });
+}
+
+/// Syncs the wallet of a [`BTCWallet`] without locking the backend, its requests only waiting
+/// for the sync to derive addresses, so that the syncs never move the derivation index back
+struct WalletSyncer {
+    wallet: Arc<Mutex<Wallet>>,
+}
+
+impl Syncer for WalletSyncer {
+    fn sync(&mut self) -> Result<(), BackendError> {
+        Ok(self
+            .wallet
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .sync(log_progress(), None)
+            .map_err(Error::from)?)
     }
 }
 
-impl BTCWallet {
-    pub fn last_unused_address(&mut self) -> Result<String, bdk::Error> {
-        let _ = self.wallet.sync(log_progress(), None);
-        Ok(self.wallet.get_address(LastUnused)?.address.to_string())
+/// Checks if the transaction signals replace-by-fee (BIP125)
+fn signals_rbf(tx: &Transaction) -> bool {
+    tx.input.iter().any(|input| input.sequence < 0xfffffffe)
+}
+
+impl PaymentBackend for BTCWallet {
+    fn last_unused_address(&mut self, _params: &InvoiceParams) -> Result<String, BackendError> {
+        let address = self.wallet().get_address(LastUnused).map_err(Error::from)?;
+        Ok(address.address.to_string())
     }
 
-    pub fn is_my_address(&mut self, addr: &str) -> Result<bool, bdk::Error> {
-        let script = Address::from_str(addr)
-            .map_err(|_| gen_err())?
-            .script_pubkey();
-        if self.wallet.is_mine(&script)? {
-            return Ok(true);
-        }
-        let _ = self.wallet.sync(log_progress(), None);
-        self.wallet.is_mine(&script)
+    fn new_address(&mut self, _params: &InvoiceParams) -> Result<String, BackendError> {
+        let address = self.wallet().get_address(New).map_err(Error::from)?;
+        Ok(address.address.to_string())
+    }
+
+    fn is_my_address(&mut self, addr: &str) -> Result<bool, BackendError> {
+        let script = self.address(addr)?.script_pubkey();
+        Ok(self.wallet().is_mine(&script).map_err(Error::from)?)
     }
 
-    pub fn balance_address(
+    fn balance_address(
         &mut self,
         addr: &str,
         from_height: Option<usize>,
-    ) -> Result<HashMap<String, String>, bdk::Error> {
-        let list = self.check_address(addr, from_height)?;
+    ) -> Result<HashMap<String, String>, BackendError> {
+        let amount: u64 = self
+            .history(addr, from_height)?
+            .iter()
+            .map(|payment| payment.amount)
+            .sum();
         let mut balances = HashMap::new();
-
-        let amount = match list.last() {
-            None => 0,
-            Some(unspent) => {
-                if unspent.height < from_height.unwrap_or(0) {
-                    0
-                } else {
-                    unspent.value
-                }
-            }
-        };
         balances.insert("btc".to_string(), amount.to_string());
         Ok(balances)
     }
 
-    pub fn network(&mut self) -> Result<String, bdk::Error> {
-        Ok(self.wallet.network().to_string())
+    fn payments(&mut self, addr: &str) -> Result<Vec<Payment>, BackendError> {
+        Ok(self.history(addr, None)?)
+    }
+
+    fn sync(&mut self) -> Result<(), BackendError> {
+        Ok(self
+            .wallet()
+            .sync(log_progress(), None)
+            .map_err(Error::from)?)
+    }
+
+    fn syncer(&mut self) -> Result<Option<Box<dyn Syncer>>, BackendError> {
+        Ok(Some(Box::new(WalletSyncer {
+            wallet: self.wallet.clone(),
+        })))
+    }
+
+    fn watch(&mut self, addr: &str) -> Result<(), BackendError> {
+        let script = self.address(addr)?.script_pubkey();
+        if self.watched.contains_key(&script) {
+            return Ok(());
+        }
+        self.client.script_subscribe(&script).map_err(Error::from)?;
+        self.watched.insert(script, addr.to_string());
+        Ok(())
+    }
+
+    fn unwatch(&mut self, addr: &str) -> Result<(), BackendError> {
+        let script = self.address(addr)?.script_pubkey();
+        if self.watched.remove(&script).is_some() {
+            self.client
+                .script_unsubscribe(&script)
+                .map_err(Error::from)?;
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Vec<Notification>, BackendError> {
+        // the notifications are read from the socket by the pings
+        let error = self
+            .ping_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(e) = error {
+            return Err(Error::from(e).into());
+        }
+        let mut notifications = vec![];
+        while let Some(header) = self.client.block_headers_pop().map_err(Error::from)? {
+            notifications.push(Notification::NewBlock(header.height as u32));
+        }
+        for (script, address) in self.watched.iter() {
+            let mut active = false;
+            while self
+                .client
+                .script_pop(script)
+                .map_err(Error::from)?
+                .is_some()
+            {
+                active = true;
+            }
+            if active {
+                notifications.push(Notification::Activity(address.clone()));
+            }
+        }
+        Ok(notifications)
+    }
+
+    fn network(&mut self) -> Result<String, BackendError> {
+        Ok(self.conf.network.to_string())
+    }
+
+    fn schema(&self) -> &str {
+        "bitcoin"
+    }
+
+    fn invoices(&self) -> InvoiceStore {
+        self.invoices.clone()
     }
 }
@@ -0,0 +1,139 @@
+//! Amounts of the lightning payments, counted in millisatoshis
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Millisatoshis in a satoshi
+const MSAT_PER_SAT: u64 = 1000;
+/// Decimals of an amount of msat written in BTC
+const BTC_DECIMALS: usize = 11;
+
+/// Amount in millisatoshis, sent as a number and read from the numbers of the recent nodes or
+/// the `<n>msat` strings of the older ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct MilliSatoshi(u64);
+
+impl MilliSatoshi {
+    pub fn from_msat(msat: u64) -> Self {
+        MilliSatoshi(msat)
+    }
+
+    /// Returns the amount of `sats`, `None` if it does not fit
+    pub fn from_sat(sats: u64) -> Option<Self> {
+        sats.checked_mul(MSAT_PER_SAT).map(MilliSatoshi)
+    }
+
+    pub fn as_msat(&self) -> u64 {
+        self.0
+    }
+
+    /// Amount in sats, rounded down as a fraction of sat can not be spent on-chain
+    pub fn to_sat(&self) -> u64 {
+        self.0 / MSAT_PER_SAT
+    }
+
+    /// Exact amount in BTC, e.g. `0.00001234567`, without trailing zeros
+    pub fn to_btc(&self) -> String {
+        let scale = 10u64.pow(BTC_DECIMALS as u32);
+        let fraction = format!("{:0width$}", self.0 % scale, width = BTC_DECIMALS);
+        match fraction.trim_end_matches('0') {
+            "" => format!("{}", self.0 / scale),
+            fraction => format!("{}.{}", self.0 / scale, fraction),
+        }
+    }
+}
+
+impl fmt::Display for MilliSatoshi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}msat", self.0)
+    }
+}
+
+/// Reads `<n>` and `<n>msat`
+impl FromStr for MilliSatoshi {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        s.strip_suffix("msat")
+            .unwrap_or(s)
+            .parse()
+            .map(MilliSatoshi)
+    }
+}
+
+impl Serialize for MilliSatoshi {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for MilliSatoshi {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MilliSatoshiVisitor;
+
+        impl<'de> Visitor<'de> for MilliSatoshiVisitor {
+            type Value = MilliSatoshi;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an amount of msat, as a number or a `<n>msat` string")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<MilliSatoshi, E> {
+                Ok(MilliSatoshi(value))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<MilliSatoshi, E> {
+                value
+                    .parse()
+                    .map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+            }
+        }
+
+        deserializer.deserialize_any(MilliSatoshiVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use amount::MilliSatoshi;
+    use serde_json;
+
+    #[test]
+    fn parse() {
+        let msat = MilliSatoshi::from_msat(1000);
+        assert_eq!(msat, serde_json::from_str("1000").unwrap());
+        assert_eq!(msat, serde_json::from_str("\"1000msat\"").unwrap());
+        assert_eq!(msat, serde_json::from_str("\"1000\"").unwrap());
+        assert!(serde_json::from_str::<MilliSatoshi>("\"lots\"").is_err());
+        assert!(serde_json::from_str::<MilliSatoshi>("\"msat\"").is_err());
+        assert!(serde_json::from_str::<MilliSatoshi>("-1").is_err());
+        assert!(serde_json::from_str::<MilliSatoshi>("1.5").is_err());
+        assert_eq!("1000", serde_json::to_string(&msat).unwrap());
+        assert_eq!("1000msat", msat.to_string());
+        assert_eq!(msat, msat.to_string().parse().unwrap());
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(
+            Some(MilliSatoshi::from_msat(21_000)),
+            MilliSatoshi::from_sat(21)
+        );
+        assert_eq!(None, MilliSatoshi::from_sat(u64::MAX));
+        assert_eq!(1, MilliSatoshi::from_msat(1999).to_sat());
+        assert_eq!(0, MilliSatoshi::from_msat(999).to_sat());
+
+        assert_eq!("0", MilliSatoshi::from_msat(0).to_btc());
+        assert_eq!("0.00000001", MilliSatoshi::from_msat(1000).to_btc());
+        assert_eq!("0.00001234567", MilliSatoshi::from_msat(1_234_567).to_btc());
+        assert_eq!("1", MilliSatoshi::from_sat(100_000_000).unwrap().to_btc());
+        assert_eq!(
+            "21000000.5",
+            MilliSatoshi::from_msat(2_100_000_050_000_000_000).to_btc()
+        );
+    }
+}
@@ -12,10 +12,18 @@ Clightning commando wallet options.
 )]
 #[derive(Debug, StructOpt, Clone, PartialEq)]
 pub struct ClightningOpts {
+    /// Data Dir
+    #[structopt(
+        name = "DATADIR",
+        env = "CLN_DATADIR",
+        long = "datadir",
+        default_value = ".btctipserver-lightning"
+    )]
+    pub data_dir: String,
     /// Lightning peer node id
     #[structopt(name = "NODEID", env = "NODEID", long = "nodeid")]
     pub nodeid: String,
-    /// Lightning peer node ip or hostname
+    /// Lightning peer node ip or hostname, with the port if not 9735, e.g. `[::1]:19735`
     #[structopt(
         name = "HOST",
         env = "HOST",
@@ -26,7 +34,19 @@ pub struct ClightningOpts {
     /// Lightning rune auth from commando plugin
     #[structopt(name = "RUNE", env = "RUNE", long = "rune")]
     pub rune: String,
-    /// Wallet output descriptor, use public keys only
+    /// Socks5 proxy reaching the node, e.g. tor at `127.0.0.1:9050`, direct connection if missing
     #[structopt(name = "PROXY", env = "PROXY", long = "proxy")]
-    pub proxy: String,
+    pub proxy: Option<String>,
+    /// Seconds to wait for the node, or the proxy, to accept a connection or to answer a request
+    #[structopt(
+        name = "CLN_TIMEOUT",
+        env = "CLN_TIMEOUT",
+        long = "timeout",
+        default_value = "30"
+    )]
+    pub timeout: u64,
+    /// Description of a reusable bolt12 offer, created once and shown in place of a new bolt11
+    /// invoice for the tips without an amount
+    #[structopt(name = "OFFER", env = "CLN_OFFER", long = "offer")]
+    pub offer: Option<String>,
 }
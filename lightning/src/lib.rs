@@ -1,261 +1,392 @@
+pub mod amount;
 pub mod config;
+pub mod listener;
+pub mod model;
+pub mod node;
+pub mod offer;
+pub mod socket;
 
+extern crate btctipserver_backend;
 pub extern crate lnsocket;
+extern crate log;
+extern crate serde;
 extern crate serde_json;
+extern crate sled;
 extern crate structopt;
 
+use amount::MilliSatoshi;
+use btctipserver_backend::invoice::InvoiceStore;
+use btctipserver_backend::Error as BackendError;
+use btctipserver_backend::{InvoiceParams, Notification, Payment, PaymentBackend};
 use config::ClightningOpts;
+use listener::{InvoiceListener, LISTENER};
+use model::{
+    Amount, CreatedInvoice, Decode, DecodeRequest, GetInfo, InvoiceInfo, InvoiceRequest, ListFunds,
+    ListInvoices, ListInvoicesRequest, NoParams, Offer, OfferRequest, RpcError,
+};
+use node::Node;
+use offer::{OfferStore, StoredOffer};
+use socket::Interrupt;
 use std::collections::HashMap;
-use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 
-/// Errors that can be thrown by the [`Wallet`](crate::wallet::Wallet)
+/// Name of the sled tree keeping the invoices
+const WALLET: &str = "btctipserver";
+/// Error code of the runes refused by commando
+const COMMANDO_UNAUTHORIZED: i64 = 0x4c51;
+/// JSON-RPC error code of the invalid command parameters
+const INVALID_PARAMS: i64 = -32602;
+
+/// Errors that can be thrown by the [`ClightningWallet`](crate::ClightningWallet)
 #[derive(Debug)]
 pub enum Error {
+    /// The connection to the node was closed
     ConnectionClosed,
-    /// Generic error
-    Generic(String),
+    /// The node, or its proxy, can not be reached
+    Unreachable(String),
+    /// The node sent a message of an unexpected type
+    UnexpectedMessage(u16),
+    /// The command does not fit in a message
+    MessageTooLong(usize),
+    /// The node refused the rune
+    Unauthorized(String),
+    /// The node refused the command parameters, e.g. a malformed bolt11
+    InvalidParams(String),
+    /// The node failed the command
+    Rpc(RpcError),
+    /// The node answer is malformed
+    Json(String),
+    /// The datadir or the database are unusable
+    Database(String),
+    /// The invoice store failed
+    Backend(BackendError),
 }
 
-pub fn gen_err() -> Error {
-    Error::Generic(format!("oh no!"))
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ConnectionClosed => write!(f, "connection to the node closed"),
+            Error::Unreachable(e) => write!(f, "node unreachable: {}", e),
+            Error::UnexpectedMessage(typ) => write!(f, "unexpected message of type {}", typ),
+            Error::MessageTooLong(len) => write!(f, "message of {} bytes too long", len),
+            Error::Unauthorized(e) => write!(f, "rune refused: {}", e),
+            Error::InvalidParams(e) => write!(f, "invalid parameters: {}", e),
+            Error::Rpc(e) => write!(f, "node error: {}", e),
+            Error::Json(e) => write!(f, "malformed answer: {}", e),
+            Error::Database(e) => write!(f, "database: {}", e),
+            Error::Backend(e) => write!(f, "{}", e),
+        }
+    }
 }
 
-pub struct ClightningWallet {
-    socket: lnsocket::lnsocket,
-    conf: ClightningOpts,
-    connected: bool,
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e.to_string())
+    }
 }
-unsafe impl Send for ClightningWallet {}
 
-impl ClightningWallet {
-    pub fn new(conf: &ClightningOpts) -> Result<Self, Error> {
-        let mut socket: lnsocket::lnsocket = unsafe {
-            let sock = lnsocket::lnsocket_create();
-            *sock
-        };
-        unsafe {
-            lnsocket::lnsocket_genkey(&mut socket);
+impl From<RpcError> for Error {
+    fn from(e: RpcError) -> Self {
+        match e.code {
+            COMMANDO_UNAUTHORIZED => Error::Unauthorized(e.message),
+            INVALID_PARAMS => Error::InvalidParams(e.message),
+            _ => Error::Rpc(e),
         }
-        let mut wallet = ClightningWallet {
-            socket: socket,
-            conf: conf.clone(),
-            connected: false,
-        };
-        wallet.connect().unwrap();
-        Ok(wallet)
     }
+}
 
-    fn connect(&mut self) -> Result<bool, Error> {
-        let res_connect = unsafe {
-            let c_node_id = CString::new(self.conf.nodeid.clone()).unwrap();
-            let c_host = CString::new(self.conf.host.clone()).unwrap();
-            let c_proxy = CString::new(self.conf.proxy.clone()).unwrap();
-            lnsocket::lnsocket_connect_tor(
-                &mut self.socket,
-                c_node_id.as_ptr(),
-                c_host.as_ptr(),
-                c_proxy.as_ptr(),
-            )
-        };
-        if res_connect == 0 {
-            return Err(Error::ConnectionClosed);
-        }
-        assert_eq!(res_connect, 1);
-        let _res_perform_init = unsafe {
-            lnsocket::lnsocket_perform_init(&mut self.socket);
-        };
-        //assert_eq!(res_perform_init, 1);
-        self.connected = true;
-        Ok(true)
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Database(e.to_string())
     }
+}
 
-    fn call(&mut self, method: String, params: String) -> Result<String, Error> {
-        return match self.call_internal(method.clone(), params.clone()) {
-            Ok(txt) => Ok(txt),
-            Err(_) => {
-                println!("reconnect");
-                let res_reconnect = self.connect()?;
-                assert_eq!(res_reconnect, true);
-                return self.call_internal(method, params);
-            }
-        };
+impl From<BackendError> for Error {
+    fn from(e: BackendError) -> Self {
+        Error::Backend(e)
     }
+}
 
-    fn call_internal(&mut self, method: String, params: String) -> Result<String, Error> {
-        let params_ = {
-            if params.is_empty() {
-                "[]".to_string()
-            } else {
-                params.clone()
+impl From<Error> for BackendError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::ConnectionClosed | Error::Unreachable(_) | Error::UnexpectedMessage(_) => {
+                BackendError::Connection(Box::new(e))
             }
-        };
-        let msg = format!(
-            "{{\"method\":\"{}\",\"params\":{},\"rune\":\"{}\"}}",
-            method, params_, self.conf.rune
-        );
-
-        println!("{}", msg);
-        let res_write = unsafe {
-            let mut cmd = vec![];
-            cmd.append(&mut 0x4c4fu16.to_be_bytes().to_vec());
-            cmd.append(&mut 1u64.to_be_bytes().to_vec());
-            cmd.append(&mut msg.as_bytes().to_vec());
-            assert_eq!(cmd.len(), msg.len() + 10);
-            lnsocket::lnsocket_write(&mut self.socket, cmd.as_ptr(), cmd.len() as u16)
-        };
-        if res_write == 0 {
-            return Err(Error::ConnectionClosed);
-        }
-        assert_eq!(res_write, 1);
-
-        unsafe {
-            let mut output = "".to_string();
-            loop {
-                let mut len = 0u16;
-                let mut t: u8 = 0;
-                let mut typ: u16 = 0;
-                let addr = &mut t as *mut u8 as usize;
-                let mut uptr = addr as *mut u8;
-                let res_recv =
-                    lnsocket::lnsocket_recv(&mut self.socket, &mut typ, &mut uptr, &mut len);
-                if res_recv == 0 {
-                    return Err(Error::ConnectionClosed);
-                }
-                assert_eq!(res_recv, 1);
-                println!("len {}", len);
-                println!("res_recv {}", res_recv);
-                println!("typ {:?}", typ);
-
-                match typ {
-                    0x594d => {
-                        // terminate
-                        *uptr.add(len as usize) = 0x00;
-                        let string = CStr::from_ptr(uptr.offset(8) as *mut i8)
-                            .to_str()
-                            .unwrap()
-                            .to_string();
-                        output = [output, string].join("");
-                        return Ok(output);
-                    }
-                    0x594b => {
-                        // continue
-                        *uptr.add(len as usize) = 0x00;
-                        let string = CStr::from_ptr(uptr.offset(8) as *mut i8)
-                            .to_str()
-                            .unwrap()
-                            .to_string();
-                        output = [output, string].join("");
-                    }
-                    18 => { //pong
-                    }
-                    _ => {
-                        // unexpected
-                        return Err(Error::ConnectionClosed);
-                    }
-                }
+            Error::Unauthorized(_) => BackendError::Config(Box::new(e)),
+            Error::MessageTooLong(_) | Error::InvalidParams(_) | Error::Rpc(_) | Error::Json(_) => {
+                BackendError::Backend(Box::new(e))
             }
+            Error::Database(e) => BackendError::Database(e.into()),
+            Error::Backend(e) => e,
         }
     }
+}
+
+pub struct ClightningWallet {
+    node: Node,
+    conf: ClightningOpts,
+    invoices: InvoiceStore,
+    offers: OfferStore,
+    /// Invoices reported paid by the listener, not polled yet
+    paid: Arc<Mutex<Vec<String>>>,
+    /// Stops the listener once the wallet is dropped
+    stop: Arc<AtomicBool>,
+    /// Shuts down the connection of the listener, waiting for the paid invoices
+    interrupt: Interrupt,
+}
 
-    fn disconnect(&mut self) {
-        unsafe {
-            lnsocket::lnsocket_destroy(&mut self.socket);
+impl ClightningWallet {
+    pub fn prepare_home_dir(datadir: &str) -> Result<PathBuf, Error> {
+        let mut dir = PathBuf::new();
+        let home = dirs_next::home_dir()
+            .ok_or_else(|| Error::Database("no home directory".to_string()))?;
+        dir.push(&home);
+        dir.push(datadir);
+
+        if !dir.exists() {
+            fs::create_dir(&dir)
+                .map_err(|e| Error::Database(format!("{}: {}", dir.display(), e)))?;
         }
+
+        dir.push("database.sled");
+        Ok(dir)
     }
 
-    fn decode(&mut self, bolt11: &str) -> Result<serde_json::Value, Error> {
-        let params = format!("[\"{}\"]", bolt11);
-        let resp = self.call("decode".to_string(), params).unwrap();
-        println!("{}", resp);
-        let result: serde_json::Value = serde_json::from_str(resp.as_str()).unwrap();
-        Ok(result)
+    pub fn new(conf: &ClightningOpts) -> Result<Self, Error> {
+        // setup database
+        let database = sled::open(Self::prepare_home_dir(&conf.data_dir)?)?;
+        let invoices = InvoiceStore::open(&database, WALLET)?;
+        let offers = OfferStore::open(&database)?;
+
+        let mut node = Node::new(conf);
+        node.connect()?;
+        let paid = Arc::new(Mutex::new(vec![]));
+        let stop = Arc::new(AtomicBool::new(false));
+        let listener = Node::new(conf);
+        let interrupt = listener.interrupt();
+        InvoiceListener::new(
+            listener,
+            database.open_tree(LISTENER)?,
+            offers.clone(),
+            paid.clone(),
+            stop.clone(),
+        )
+        .start();
+
+        Ok(ClightningWallet {
+            node,
+            conf: conf.clone(),
+            invoices,
+            offers,
+            paid,
+            stop,
+            interrupt,
+        })
     }
 
-    fn getinfo(&mut self) -> Result<serde_json::Value, Error> {
-        let resp = self.call("getinfo".to_string(), "".to_string()).unwrap();
-        println!("{}", resp);
-        let result: serde_json::Value = serde_json::from_str(resp.as_str()).unwrap();
-        Ok(result)
+    fn decode(&mut self, string: &str) -> Result<Decode, Error> {
+        self.node.request("decode", &DecodeRequest { string })
     }
 
-    fn new_invoice(&mut self, msat: String, label: String) -> Result<serde_json::Value, Error> {
-        let params = format!("[\"{}\", \"{}\", \"{}\"]", msat, label, "");
-        let resp = self.call("invoice".to_string(), params).unwrap();
-        println!("{}", resp);
-        let result: serde_json::Value = serde_json::from_str(resp.as_str()).unwrap();
-        Ok(result)
+    fn getinfo(&mut self) -> Result<GetInfo, Error> {
+        self.node.request("getinfo", &NoParams::default())
     }
 
-    fn get_invoice(&mut self, payment_hash: &str) -> Result<serde_json::Value, Error> {
-        let params = format!("[null,null,\"{}\"]", payment_hash);
-        let resp = self.call("listinvoices".to_string(), params).unwrap();
-        println!("{}", resp);
-        let result: serde_json::Value = serde_json::from_str(resp.as_str()).unwrap();
-        Ok(result)
+    fn new_invoice(&mut self, params: &InvoiceParams) -> Result<CreatedInvoice, Error> {
+        let request = InvoiceRequest {
+            amount_msat: match params.amount_msat {
+                Some(msat) => Amount::Msat(MilliSatoshi::from_msat(msat)),
+                None => Amount::Any,
+            },
+            label: &params.id,
+            description: params.description.as_deref().unwrap_or(""),
+            expiry: params.expiry,
+            fallbacks: params.fallback.iter().map(|f| f.as_str()).collect(),
+            deschashonly: match params.description_hash_only {
+                true => Some(true),
+                false => None,
+            },
+        };
+        self.node.request("invoice", &request)
     }
-}
-impl Drop for ClightningWallet {
-    fn drop(&mut self) {
-        self.disconnect();
-        println!("exit");
+
+    /// Returns the invoice labelled `label`, i.e. the one issued for the invoice with that id
+    pub fn find_invoice(&mut self, label: &str) -> Result<Option<InvoiceInfo>, Error> {
+        let request = ListInvoicesRequest {
+            label: Some(label),
+            ..Default::default()
+        };
+        let list: ListInvoices = self.node.request("listinvoices", &request)?;
+        Ok(list.invoices.into_iter().next())
+    }
+
+    /// Returns the on-chain outputs and the channels of the node
+    pub fn list_funds(&mut self) -> Result<ListFunds, Error> {
+        self.node.request("listfunds", &NoParams::default())
+    }
+
+    /// Creates the bolt12 offer of `request`
+    pub fn offer(&mut self, request: &OfferRequest) -> Result<Offer, Error> {
+        self.node.request("offer", request)
+    }
+
+    fn get_invoice(&mut self, payment_hash: &str) -> Result<Option<InvoiceInfo>, Error> {
+        let request = ListInvoicesRequest {
+            payment_hash: Some(payment_hash),
+            ..Default::default()
+        };
+        let list: ListInvoices = self.node.request("listinvoices", &request)?;
+        Ok(list.invoices.into_iter().next())
     }
 }
 impl ClightningWallet {
-    pub fn last_unused_address(&mut self) -> Result<String, Error> {
-        //let address = self.wallet.get_new_address().map_err(|_| gen_err())?;
-        if !self.connected {
-            self.connect().unwrap();
+    /// Returns a new bolt11 invoice described by `params`
+    fn bolt11(&mut self, params: &InvoiceParams) -> Result<String, Error> {
+        Ok(self.new_invoice(params)?.bolt11)
+    }
+
+    /// Returns the reusable offer described by `description`, created on the node only once
+    fn bolt12(&mut self, description: &str) -> Result<String, Error> {
+        if let Some(offer) = self.offers.get()? {
+            if offer.description == description {
+                return Ok(offer.bolt12);
+            }
         }
-        //let params = format!("[\"%dmsat\", \"%s\", \"%s\"]",
-        //	params.Msatoshi, label, params.Description)
-
-        let charset = "abcdefghijklmnopqrstuvwxyz";
-        let label = random_string::generate(8, charset);
-        let result = self.new_invoice("any".to_string(), label).unwrap();
-        println!("{}", result);
-        let bolt11 = result["result"]["bolt11"].as_str().unwrap();
-        Ok(bolt11.to_string())
+        let request = OfferRequest {
+            amount: Amount::Any,
+            description,
+            label: None,
+            issuer: None,
+            single_use: None,
+        };
+        let offer = self.offer(&request)?;
+        self.offers.insert(&StoredOffer {
+            offer_id: offer.offer_id,
+            bolt12: offer.bolt12.clone(),
+            description: description.to_string(),
+        })?;
+        Ok(offer.bolt12)
+    }
+
+    /// Returns the invoices paid to `addr`, a bolt11 invoice or the offer
+    fn paid_invoices(&mut self, addr: &str) -> Result<Vec<InvoiceInfo>, Error> {
+        let invoices = match self.offers.find(addr)? {
+            Some(offer) => {
+                let request = ListInvoicesRequest {
+                    offer_id: Some(&offer.offer_id),
+                    ..Default::default()
+                };
+                let list: ListInvoices = self.node.request("listinvoices", &request)?;
+                list.invoices
+            }
+            None => {
+                let payment_hash = self
+                    .decode(addr)?
+                    .payment_hash
+                    .ok_or_else(|| Error::Json("no payment_hash".to_string()))?;
+                self.get_invoice(&payment_hash)?.into_iter().collect()
+            }
+        };
+        Ok(invoices
+            .into_iter()
+            .filter(|invoice| invoice.received() > MilliSatoshi::default())
+            .collect())
+    }
+}
+impl PaymentBackend for ClightningWallet {
+    fn last_unused_address(&mut self, params: &InvoiceParams) -> Result<String, BackendError> {
+        if let Some(description) = self.conf.offer.clone() {
+            return Ok(self.bolt12(&description)?);
+        }
+        Ok(self.bolt11(params)?)
+    }
+
+    fn invoice_address(&mut self, params: &InvoiceParams) -> Result<String, BackendError> {
+        Ok(self.bolt11(params)?)
     }
 
-    pub fn is_my_address(&mut self, addr: &str) -> Result<bool, Error> {
-        let result = self.decode(addr).unwrap();
-        println!("{}", result);
-        let payee = result["result"]["payee"].as_str().unwrap();
-        let valid = result["result"]["valid"].as_bool().unwrap();
-        Ok(valid && payee == self.conf.nodeid)
+    fn is_my_address(&mut self, addr: &str) -> Result<bool, BackendError> {
+        if self.offers.find(addr)?.is_some() {
+            return Ok(true);
+        }
+        let decoded = match self.decode(addr) {
+            Ok(decoded) => decoded,
+            // the node refuses to decode strings which are not invoices
+            Err(e @ Error::InvalidParams(_)) | Err(e @ Error::Rpc(_)) => {
+                return Err(BackendError::InvalidAddress(Box::new(e)))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(decoded.valid && decoded.payee.as_deref() == Some(self.conf.nodeid.as_str()))
     }
 
-    pub fn balance_address(
+    fn balance_address(
         &mut self,
         addr: &str,
         _from_height: Option<usize>,
-    ) -> Result<HashMap<String, String>, Error> {
+    ) -> Result<HashMap<String, String>, BackendError> {
         let mut balances = HashMap::new();
-        let decoded = self.decode(addr).unwrap();
-        let payment_hash = decoded["result"]["payment_hash"].as_str().unwrap();
-        let invoices = self.get_invoice(payment_hash).unwrap();
-        println!("{}", invoices);
-        let invoice = &invoices["result"]["invoices"][0];
-        let msat = match invoice["status"].as_str().unwrap() {
-            "unpaid" => 0,
-            "expired" => 0,
-            "paid" => {
-                let msat = invoice["amount_received_msat"].as_str().unwrap();
-                let amount = &msat[0..msat.len() - 3];
-                amount.parse::<u64>().unwrap()
-            }
-            _ => 0,
-        };
-        balances.insert(addr.to_string(), msat.to_string());
+        let received: u64 = self.payments(addr)?.iter().map(|p| p.amount).sum();
+        // in sats, as the on-chain balances
+        balances.insert("btc".to_string(), received.to_string());
         Ok(balances)
     }
 
-    pub fn network(&mut self) -> Result<String, Error> {
-        if !self.connected {
-            self.connect().unwrap();
-        }
-        let result = self.getinfo().unwrap();
-        let network = result["result"]["network"].as_str().unwrap();
-        Ok(format!("Lightning {}", network))
+    fn payments(&mut self, addr: &str) -> Result<Vec<Payment>, BackendError> {
+        let is_offer = self.offers.find(addr)?.is_some();
+        Ok(self
+            .paid_invoices(addr)?
+            .into_iter()
+            .map(|invoice| Payment {
+                txid: None,
+                amount: invoice.received().to_sat(),
+                payment_hash: Some(invoice.payment_hash),
+                confirmations: None,
+                rbf: false,
+                // tells apart the payments to the offer made for each invoice issued with it
+                paid_at: match is_offer {
+                    true => invoice.paid_at,
+                    false => None,
+                },
+            })
+            .collect())
+    }
+
+    /// Reports the invoices paid since the last poll, as detected by the listener
+    fn poll(&mut self) -> Result<Vec<Notification>, BackendError> {
+        let paid: Vec<String> = self
+            .paid
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .drain(..)
+            .collect();
+        Ok(paid.into_iter().map(Notification::Activity).collect())
+    }
+
+    fn network(&mut self) -> Result<String, BackendError> {
+        let info = self.getinfo()?;
+        Ok(format!("Lightning {}", info.network))
+    }
+
+    fn schema(&self) -> &str {
+        "lightning"
+    }
+
+    fn invoices(&self) -> InvoiceStore {
+        self.invoices.clone()
+    }
+}
+
+impl Drop for ClightningWallet {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.interrupt.interrupt();
     }
 }
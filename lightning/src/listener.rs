@@ -0,0 +1,223 @@
+//! Detects the paid invoices as soon as the node settles them, long-polling `waitanyinvoice`
+
+use log::{error, warn};
+use sled;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use model::{InvoiceInfo, WaitAnyInvoiceRequest};
+use node::Node;
+use offer::OfferStore;
+use Error;
+
+/// Name of the sled tree keeping the state of the listener
+pub const LISTENER: &str = "listener";
+/// Key of the `pay_index` of the last paid invoice reported
+const LASTPAY_INDEX: &[u8] = b"lastpay_index";
+/// Seconds each `waitanyinvoice` waits before being sent again, so that the listener can stop
+const WAIT_TIMEOUT: u64 = 60;
+/// Error code of the `waitanyinvoice` which timed out
+const WAIT_TIMED_OUT: i64 = 904;
+/// Pause after a failed `waitanyinvoice`, the node backoff spaces the reconnections
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Waits for the invoices paid to the node, on a connection of its own, queueing their
+/// invoice strings, or the one of their offer, for
+/// [`poll`](btctipserver_backend::PaymentBackend::poll).
+///
+/// The `pay_index` of the last one is persisted, so that the invoices paid while the server
+/// was down are reported after a restart. Without one, all the paid invoices of the node
+/// are reported once.
+pub struct InvoiceListener {
+    node: Node,
+    tree: sled::Tree,
+    offers: OfferStore,
+    paid: Arc<Mutex<Vec<String>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl InvoiceListener {
+    pub fn new(
+        node: Node,
+        tree: sled::Tree,
+        offers: OfferStore,
+        paid: Arc<Mutex<Vec<String>>>,
+        stop: Arc<AtomicBool>,
+    ) -> Self {
+        InvoiceListener {
+            // the node holds the waitanyinvoice requests
+            node: node.waiting(Duration::from_secs(WAIT_TIMEOUT)),
+            tree,
+            offers,
+            paid,
+            stop,
+        }
+    }
+
+    /// Returns the `pay_index` of the last paid invoice reported, if any
+    pub fn lastpay_index(&self) -> Result<Option<u64>, Error> {
+        match self.tree.get(LASTPAY_INDEX)? {
+            Some(bytes) if bytes.len() == 8 => {
+                let mut index = [0u8; 8];
+                index.copy_from_slice(&bytes);
+                Ok(Some(u64::from_be_bytes(index)))
+            }
+            Some(_) => Err(Error::Database("malformed lastpay_index".to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Queues the paid `invoice`, then moves past it
+    fn paid(&mut self, invoice: InvoiceInfo) -> Result<(), Error> {
+        let offer = match &invoice.local_offer_id {
+            Some(offer_id) => self.offers.get_by_id(offer_id)?,
+            None => None,
+        };
+        let invstring = match offer {
+            Some(offer) => Some(offer.bolt12),
+            None => invoice.bolt11.or(invoice.bolt12),
+        };
+        if let Some(invstring) = invstring {
+            self.paid
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(invstring);
+        }
+        if let Some(index) = invoice.pay_index {
+            self.tree.insert(LASTPAY_INDEX, &index.to_be_bytes())?;
+            self.tree.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the next paid invoice
+    fn wait(&mut self) -> Result<InvoiceInfo, Error> {
+        let request = WaitAnyInvoiceRequest {
+            lastpay_index: self.lastpay_index()?,
+            timeout: Some(WAIT_TIMEOUT),
+        };
+        self.node.request("waitanyinvoice", &request)
+    }
+
+    /// Listens from a background thread until stopped, or refused by the node
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            while !self.stop.load(Ordering::Relaxed) {
+                match self.wait() {
+                    Ok(invoice) => {
+                        if let Err(e) = self.paid(invoice) {
+                            warn!("unable to record the paid invoice: {}", e);
+                        }
+                    }
+                    Err(Error::Rpc(ref e)) if e.code == WAIT_TIMED_OUT => {}
+                    // interrupted by the wallet once dropped
+                    Err(_) if self.stop.load(Ordering::Relaxed) => {}
+                    Err(e @ Error::Unauthorized(_)) => {
+                        error!("{}, the paid invoices are detected on sync only", e);
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("waitanyinvoice failed: {}", e);
+                        thread::sleep(RETRY_DELAY);
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use config::ClightningOpts;
+    use listener::{InvoiceListener, LISTENER};
+    use model::InvoiceInfo;
+    use node::Node;
+    use offer::{OfferStore, StoredOffer};
+    use serde_json;
+    use sled;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+
+    fn open(database: &sled::Db) -> InvoiceListener {
+        let conf = ClightningOpts {
+            data_dir: "".to_string(),
+            nodeid: "".to_string(),
+            host: "127.0.0.1".to_string(),
+            rune: "".to_string(),
+            proxy: None,
+            timeout: 1,
+            offer: None,
+        };
+        InvoiceListener::new(
+            Node::new(&conf),
+            database.open_tree(LISTENER).unwrap(),
+            OfferStore::open(database).unwrap(),
+            Arc::new(Mutex::new(vec![])),
+            Arc::new(AtomicBool::new(false)),
+        )
+    }
+
+    fn invoice(bolt11: &str, pay_index: u64) -> InvoiceInfo {
+        let json = format!(
+            r#"{{"label": "abc", "bolt11": "{}", "payment_hash": "00", "status": "paid",
+                "amount_received_msat": 1000, "pay_index": {}, "expires_at": 0}}"#,
+            bolt11, pay_index
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn paid() {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let mut listener = open(&database);
+        assert_eq!(None, listener.lastpay_index().unwrap());
+
+        listener.paid(invoice("lnbc1", 1)).unwrap();
+        listener.paid(invoice("lnbc2", 2)).unwrap();
+        assert_eq!(Some(2), listener.lastpay_index().unwrap());
+        assert_eq!(
+            vec!["lnbc1".to_string(), "lnbc2".to_string()],
+            *listener.paid.lock().unwrap()
+        );
+
+        // resumed from the same database
+        let resumed = open(&database);
+        assert_eq!(Some(2), resumed.lastpay_index().unwrap());
+    }
+
+    #[test]
+    fn paid_offer() {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let mut listener = open(&database);
+        let offer = StoredOffer {
+            offer_id: "ff".to_string(),
+            bolt12: "lno1".to_string(),
+            description: "Tips".to_string(),
+        };
+        listener.offers.insert(&offer).unwrap();
+
+        let json = r#"{"label": "abc", "bolt12": "lni1", "payment_hash": "00", "status": "paid",
+            "amount_received_msat": 1000, "pay_index": 1, "expires_at": 0, "local_offer_id": "ff"}"#;
+        listener.paid(serde_json::from_str(json).unwrap()).unwrap();
+        let unknown = json.replace(r#""ff""#, r#""ee""#);
+        listener
+            .paid(serde_json::from_str(&unknown).unwrap())
+            .unwrap();
+        // still credited to the superseded offer
+        listener
+            .offers
+            .insert(&StoredOffer {
+                offer_id: "fe".to_string(),
+                bolt12: "lno2".to_string(),
+                description: "Donations".to_string(),
+            })
+            .unwrap();
+        listener.paid(serde_json::from_str(json).unwrap()).unwrap();
+        assert_eq!(
+            vec!["lno1".to_string(), "lni1".to_string(), "lno1".to_string()],
+            *listener.paid.lock().unwrap()
+        );
+    }
+}
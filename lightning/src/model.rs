@@ -0,0 +1,311 @@
+//! Requests and responses of the Core Lightning commands sent over commando
+
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use amount::MilliSatoshi;
+
+/// Command sent to the node, authorized by `rune`
+#[derive(Debug, Serialize)]
+pub struct Command<'a, P: Serialize> {
+    pub method: &'a str,
+    pub params: &'a P,
+    pub rune: &'a str,
+}
+
+/// Answer of the node, either a result or an error
+#[derive(Debug, Deserialize)]
+pub struct Response<T> {
+    pub result: Option<T>,
+    pub error: Option<RpcError>,
+}
+
+/// Error returned by the node, e.g. for an unknown command or an invalid rune
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+/// Parameters of the commands taking none
+#[derive(Debug, Default, Serialize)]
+pub struct NoParams {}
+
+/// Amount of a new invoice, `any` lets the payer choose it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Amount {
+    Msat(MilliSatoshi),
+    Any,
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Amount::Msat(msat) => msat.serialize(serializer),
+            Amount::Any => serializer.serialize_str("any"),
+        }
+    }
+}
+
+/// Answer of `getinfo`
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetInfo {
+    pub id: String,
+    #[serde(default)]
+    pub alias: Option<String>,
+    pub network: String,
+    pub blockheight: u32,
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+/// Parameters of `decode`
+#[derive(Debug, Serialize)]
+pub struct DecodeRequest<'a> {
+    pub string: &'a str,
+}
+
+/// Answer of `decode`, with the fields of the bolt11 and bolt12 strings
+#[derive(Debug, Clone, Deserialize)]
+pub struct Decode {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub valid: bool,
+    /// Node paid by a bolt11 invoice
+    #[serde(default)]
+    pub payee: Option<String>,
+    #[serde(default)]
+    pub payment_hash: Option<String>,
+    #[serde(default)]
+    pub amount_msat: Option<MilliSatoshi>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    #[serde(default)]
+    pub expiry: Option<u64>,
+}
+
+/// Parameters of `invoice`
+#[derive(Debug, Serialize)]
+pub struct InvoiceRequest<'a> {
+    pub amount_msat: Amount,
+    pub label: &'a str,
+    pub description: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deschashonly: Option<bool>,
+}
+
+/// Answer of `invoice`
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreatedInvoice {
+    pub bolt11: String,
+    pub payment_hash: String,
+    pub expires_at: u64,
+}
+
+/// Parameters of `listinvoices`, filtering by any of the fields set
+#[derive(Debug, Default, Serialize)]
+pub struct ListInvoicesRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invstring: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_hash: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offer_id: Option<&'a str>,
+}
+
+/// Answer of `listinvoices`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListInvoices {
+    pub invoices: Vec<InvoiceInfo>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvoiceStatus {
+    Unpaid,
+    Paid,
+    Expired,
+}
+
+/// An invoice of the node, as listed by `listinvoices` and returned by `waitanyinvoice`
+#[derive(Debug, Clone, Deserialize)]
+pub struct InvoiceInfo {
+    pub label: String,
+    #[serde(default)]
+    pub bolt11: Option<String>,
+    #[serde(default)]
+    pub bolt12: Option<String>,
+    pub payment_hash: String,
+    pub status: InvoiceStatus,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub amount_msat: Option<MilliSatoshi>,
+    #[serde(default)]
+    pub amount_received_msat: Option<MilliSatoshi>,
+    /// Index of the payment among all the ones received by the node
+    #[serde(default)]
+    pub pay_index: Option<u64>,
+    #[serde(default)]
+    pub paid_at: Option<u64>,
+    pub expires_at: u64,
+    /// Offer the invoice was requested for
+    #[serde(default)]
+    pub local_offer_id: Option<String>,
+}
+
+impl InvoiceInfo {
+    /// Amount received, 0 until paid
+    pub fn received(&self) -> MilliSatoshi {
+        match self.status {
+            InvoiceStatus::Paid => self.amount_received_msat.unwrap_or_default(),
+            InvoiceStatus::Unpaid | InvoiceStatus::Expired => MilliSatoshi::from_msat(0),
+        }
+    }
+}
+
+/// Parameters of `waitanyinvoice`
+#[derive(Debug, Default, Serialize)]
+pub struct WaitAnyInvoiceRequest {
+    /// Waits for the payments after the one with this `pay_index`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lastpay_index: Option<u64>,
+    /// Seconds to wait before failing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+/// Answer of `listfunds`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListFunds {
+    pub outputs: Vec<FundOutput>,
+    pub channels: Vec<FundChannel>,
+}
+
+/// An on-chain output of the node wallet
+#[derive(Debug, Clone, Deserialize)]
+pub struct FundOutput {
+    pub txid: String,
+    pub output: u32,
+    pub amount_msat: MilliSatoshi,
+    pub status: String,
+    #[serde(default)]
+    pub address: Option<String>,
+}
+
+/// A channel of the node, with its balance
+#[derive(Debug, Clone, Deserialize)]
+pub struct FundChannel {
+    pub peer_id: String,
+    pub our_amount_msat: MilliSatoshi,
+    pub amount_msat: MilliSatoshi,
+    pub connected: bool,
+    pub state: String,
+}
+
+/// Parameters of `offer`
+#[derive(Debug, Serialize)]
+pub struct OfferRequest<'a> {
+    pub amount: Amount,
+    pub description: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub single_use: Option<bool>,
+}
+
+/// Answer of `offer`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Offer {
+    pub offer_id: String,
+    pub active: bool,
+    pub single_use: bool,
+    pub bolt12: String,
+    pub used: bool,
+    #[serde(default)]
+    pub created: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use amount::MilliSatoshi;
+    use model::{Amount, Decode, GetInfo, InvoiceRequest, InvoiceStatus, ListInvoices, Response};
+    use serde_json;
+
+    #[test]
+    fn invoice_request() {
+        let request = InvoiceRequest {
+            amount_msat: Amount::Any,
+            label: "abc",
+            description: "Tea \"&\" cake",
+            expiry: None,
+            fallbacks: vec![],
+            deschashonly: None,
+        };
+        assert_eq!(
+            r#"{"amount_msat":"any","label":"abc","description":"Tea \"&\" cake"}"#,
+            serde_json::to_string(&request).unwrap()
+        );
+        let request = InvoiceRequest {
+            amount_msat: Amount::Msat(MilliSatoshi::from_msat(1000)),
+            expiry: Some(3600),
+            fallbacks: vec!["bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq"],
+            ..request
+        };
+        assert_eq!(
+            r#"{"amount_msat":1000,"label":"abc","description":"Tea \"&\" cake","expiry":3600,"fallbacks":["bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq"]}"#,
+            serde_json::to_string(&request).unwrap()
+        );
+    }
+
+    #[test]
+    fn responses() {
+        let json = r#"{"result":{"id":"02aa","alias":"node","color":"ffffff","network":"bitcoin","blockheight":800000}}"#;
+        let response: Response<GetInfo> = serde_json::from_str(json).unwrap();
+        assert_eq!("bitcoin", response.result.unwrap().network);
+
+        let json = r#"{"error":{"code":19537,"message":"Not authorized: Failed: restriction"}}"#;
+        let response: Response<GetInfo> = serde_json::from_str(json).unwrap();
+        assert!(response.result.is_none());
+        assert_eq!(19537, response.error.unwrap().code);
+
+        let json = r#"{"result":{"type":"bolt11 invoice","currency":"bc","valid":true,"payee":"02aa","payment_hash":"00ff","amount_msat":"1000msat"}}"#;
+        let decode = serde_json::from_str::<Response<Decode>>(json)
+            .unwrap()
+            .result
+            .unwrap();
+        assert!(decode.valid);
+        assert_eq!(Some(MilliSatoshi::from_msat(1000)), decode.amount_msat);
+
+        let json = r#"{"result":{"invoices":[
+            {"label":"a","bolt11":"lnbc1","payment_hash":"00ff","status":"paid","amount_msat":1000,"amount_received_msat":1200,"pay_index":3,"paid_at":1650000100,"expires_at":1650003600},
+            {"label":"b","bolt11":"lnbc2","payment_hash":"01ff","status":"expired","expires_at":1650003600}
+        ]}}"#;
+        let invoices = serde_json::from_str::<Response<ListInvoices>>(json)
+            .unwrap()
+            .result
+            .unwrap()
+            .invoices;
+        assert_eq!(InvoiceStatus::Paid, invoices[0].status);
+        assert_eq!(MilliSatoshi::from_msat(1200), invoices[0].received());
+        assert_eq!(Some(3), invoices[0].pay_index);
+        assert_eq!(MilliSatoshi::from_msat(0), invoices[1].received());
+    }
+}
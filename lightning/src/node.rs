@@ -0,0 +1,124 @@
+//! Commando client of a lightning node, reconnecting after failures
+
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use std::time::{Duration, Instant};
+
+use config::ClightningOpts;
+use model::{Command, Response};
+use socket::{Backoff, Interrupt, LnSocket, NodeAddr, DEFAULT_PROXY_PORT};
+use Error;
+
+/// Delay before the first reconnection attempt, doubled after each failure
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Longest delay between two reconnection attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+pub struct Node {
+    /// Connection to the node, opened on the first request and after failures
+    socket: Option<LnSocket>,
+    /// Spaces the reconnection attempts while the node is unreachable
+    backoff: Backoff,
+    /// Longest wait for a reply, or for a request to be sent
+    io_timeout: Duration,
+    /// Shuts the connection down for good from another thread
+    interrupt: Interrupt,
+    conf: ClightningOpts,
+}
+
+impl Node {
+    /// Returns a client of the node of `conf`, connecting on the first request
+    pub fn new(conf: &ClightningOpts) -> Self {
+        Node {
+            socket: None,
+            backoff: Backoff::new(RECONNECT_DELAY, MAX_RECONNECT_DELAY),
+            io_timeout: Duration::from_secs(conf.timeout),
+            interrupt: Interrupt::default(),
+            conf: conf.clone(),
+        }
+    }
+
+    /// Waits `wait` longer for the replies, e.g. to the requests the node holds on purpose
+    pub fn waiting(mut self, wait: Duration) -> Self {
+        self.io_timeout += wait;
+        self
+    }
+
+    /// Returns the handle shutting the connection down from another thread, failing the
+    /// requests from then on
+    pub fn interrupt(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    /// Connects to the node, failing right away while waiting to retry after failures
+    pub fn connect(&mut self) -> Result<(), Error> {
+        self.socket = None;
+        if self.interrupt.is_interrupted() {
+            return Err(Error::ConnectionClosed);
+        }
+        if let Some(wait) = self.backoff.wait(Instant::now()) {
+            let e = format!("retrying in {}s", wait.as_secs() + 1);
+            return Err(Error::Unreachable(e));
+        }
+        let host: NodeAddr = self.conf.host.parse()?;
+        let proxy = match &self.conf.proxy {
+            Some(proxy) => Some(NodeAddr::parse(proxy, DEFAULT_PROXY_PORT)?),
+            None => None,
+        };
+        let timeout = Duration::from_secs(self.conf.timeout);
+        let nodeid = &self.conf.nodeid;
+        match LnSocket::connect(nodeid, &host, proxy.as_ref(), timeout, self.io_timeout) {
+            Ok(mut socket) => {
+                socket.interrupt_with(&self.interrupt);
+                self.backoff.reset();
+                self.socket = Some(socket);
+                Ok(())
+            }
+            Err(e) => {
+                self.backoff.fail(Instant::now());
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends the json command `msg`, reconnecting once if the connection is lost
+    fn call(&mut self, msg: &str) -> Result<String, Error> {
+        if let Some(socket) = self.socket.as_mut() {
+            match socket.call(msg) {
+                Ok(reply) => return Ok(reply),
+                // the connection is still fine
+                Err(e @ Error::Json(_)) | Err(e @ Error::MessageTooLong(_)) => return Err(e),
+                Err(e) if self.interrupt.is_interrupted() => return Err(e),
+                Err(e) => warn!("reconnecting to the node after {}", e),
+            }
+        }
+        self.connect()?;
+        match self.socket.as_mut() {
+            Some(socket) => socket.call(msg),
+            None => Err(Error::ConnectionClosed),
+        }
+    }
+
+    /// Calls `method` with `params`, returning the result or the error of the node
+    pub fn request<P: Serialize, T: DeserializeOwned>(
+        &mut self,
+        method: &str,
+        params: &P,
+    ) -> Result<T, Error> {
+        let command = Command {
+            method,
+            params,
+            rune: &self.conf.rune,
+        };
+        let msg = serde_json::to_string(&command)?;
+        let resp = self.call(&msg)?;
+        let response: Response<T> = serde_json::from_str(resp.as_str())?;
+        match (response.result, response.error) {
+            (_, Some(error)) => Err(error.into()),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(Error::Json(format!("no result for {}", method))),
+        }
+    }
+}
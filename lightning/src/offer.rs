@@ -0,0 +1,109 @@
+//! Reusable bolt12 offers of the wallet, created once on the node and persisted.
+//!
+//! A new description supersedes the current offer, which is kept to credit its late payments.
+
+use serde::{Deserialize, Serialize};
+use serde_json;
+use sled;
+
+use Error;
+
+/// Name of the sled tree keeping the offers, by offer id
+pub const OFFERS: &str = "offers";
+/// Key of a copy of the current offer
+const CURRENT: &[u8] = b"current";
+
+/// Offer shown for the tips without an amount
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredOffer {
+    pub offer_id: String,
+    pub bolt12: String,
+    /// Description the offer was created with, a new one replaces it
+    pub description: String,
+}
+
+#[derive(Clone)]
+pub struct OfferStore {
+    tree: sled::Tree,
+}
+
+impl OfferStore {
+    pub fn open(db: &sled::Db) -> Result<Self, Error> {
+        Ok(OfferStore {
+            tree: db.open_tree(OFFERS)?,
+        })
+    }
+
+    /// Returns the current offer, if already created
+    pub fn get(&self) -> Result<Option<StoredOffer>, Error> {
+        match self.tree.get(CURRENT)? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the offer `offer_id`, current or superseded
+    pub fn get_by_id(&self, offer_id: &str) -> Result<Option<StoredOffer>, Error> {
+        match self.tree.get(offer_id.as_bytes())? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            // older databases only kept the current offer
+            None => Ok(self.get()?.filter(|offer| offer.offer_id == offer_id)),
+        }
+    }
+
+    /// Returns the offer whose invoice string is `bolt12`, current or superseded
+    pub fn find(&self, bolt12: &str) -> Result<Option<StoredOffer>, Error> {
+        for entry in self.tree.iter() {
+            let (_, bytes) = entry?;
+            let offer: StoredOffer = serde_json::from_slice(&bytes)?;
+            if offer.bolt12 == bolt12 {
+                return Ok(Some(offer));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Stores `offer` as the current one, keeping the one it supersedes
+    pub fn insert(&self, offer: &StoredOffer) -> Result<(), Error> {
+        let bytes = serde_json::to_vec(offer)?;
+        self.tree.insert(offer.offer_id.as_bytes(), bytes.clone())?;
+        self.tree.insert(CURRENT, bytes)?;
+        self.tree.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use offer::{OfferStore, StoredOffer};
+    use sled;
+
+    #[test]
+    fn current_offer() {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let offers = OfferStore::open(&database).unwrap();
+        assert_eq!(None, offers.get().unwrap());
+
+        let offer = StoredOffer {
+            offer_id: "00".to_string(),
+            bolt12: "lno1".to_string(),
+            description: "Tips".to_string(),
+        };
+        offers.insert(&offer).unwrap();
+        assert_eq!(Some(offer.clone()), offers.get().unwrap());
+        assert_eq!(Some(offer.clone()), offers.find("lno1").unwrap());
+        assert_eq!(None, offers.find("lno2").unwrap());
+
+        // the superseded offer stays findable
+        let new = StoredOffer {
+            offer_id: "01".to_string(),
+            bolt12: "lno2".to_string(),
+            description: "Donations".to_string(),
+        };
+        offers.insert(&new).unwrap();
+        assert_eq!(Some(new.clone()), offers.get().unwrap());
+        assert_eq!(Some(new), offers.find("lno2").unwrap());
+        assert_eq!(Some(offer.clone()), offers.find("lno1").unwrap());
+        assert_eq!(Some(offer), offers.get_by_id("00").unwrap());
+    }
+}
@@ -0,0 +1,683 @@
+//! Connection to a lightning node, carrying the commando requests of the wallet
+
+use lnsocket;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::slice;
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use Error;
+
+/// Commando request, the payload is the request id followed by the json command
+const COMMANDO_CMD: u16 = 0x4c4f;
+/// Chunk of a commando reply, more follow
+const COMMANDO_REPLY_CONTINUES: u16 = 0x594b;
+/// Last chunk of a commando reply
+const COMMANDO_REPLY_TERM: u16 = 0x594d;
+const PING: u16 = 18;
+const PONG: u16 = 19;
+/// Largest message the lnsocket library can write
+const MAX_MESSAGE: usize = u16::MAX as usize;
+/// Port of the nodes given without one
+pub const DEFAULT_PORT: u16 = 9735;
+/// Port of the socks5 proxies given without one
+pub const DEFAULT_PROXY_PORT: u16 = 9050;
+
+/// Host and port of a node or proxy, written `host`, `host:port`, `ipv6`, `[ipv6]` or
+/// `[ipv6]:port`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeAddr {
+    pub host: String,
+    pub port: u16,
+}
+
+impl NodeAddr {
+    /// Parses `addr`, with `port` if it has none
+    pub fn parse(addr: &str, port: u16) -> Result<Self, Error> {
+        let invalid = || Error::InvalidParams(format!("invalid address {}", addr));
+        let addr = addr.trim();
+        let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            match rest {
+                "" => (host, port),
+                _ => {
+                    let port = rest.strip_prefix(':').ok_or_else(invalid)?;
+                    (host, port.parse().map_err(|_| invalid())?)
+                }
+            }
+        } else {
+            match addr.rsplit_once(':') {
+                // a bare ipv6 has several colons
+                Some((host, _)) if host.contains(':') => (addr, port),
+                Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+                None => (addr, port),
+            }
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(NodeAddr {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for NodeAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl FromStr for NodeAddr {
+    type Err = Error;
+
+    fn from_str(addr: &str) -> Result<Self, Error> {
+        NodeAddr::parse(addr, DEFAULT_PORT)
+    }
+}
+
+/// Delays the connection attempts after consecutive failures, doubling the delay each time
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base,
+            max,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    /// Returns the time left before the next attempt, if it is too early for it
+    pub fn wait(&self, now: Instant) -> Option<Duration> {
+        match self.retry_at {
+            Some(retry_at) if retry_at > now => Some(retry_at - now),
+            _ => None,
+        }
+    }
+
+    /// Records a failed attempt at `now`
+    pub fn fail(&mut self, now: Instant) {
+        let delay = self.base * 2u32.saturating_pow(cmp::min(self.failures, 16));
+        self.failures += 1;
+        self.retry_at = Some(now + cmp::min(delay, self.max));
+    }
+
+    /// Records a successful attempt
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+}
+
+/// Sends and receives the messages of an established lightning connection
+pub trait Transport {
+    /// Writes a message, made of its type and payload
+    fn write(&mut self, msg: &[u8]) -> Result<(), Error>;
+
+    /// Waits for the next message, returning its type and payload
+    fn recv(&mut self) -> Result<(u16, Vec<u8>), Error>;
+}
+
+/// Borrows the descriptor `fd` of a connection as a stream, the library closes it
+fn stream(fd: RawFd) -> ManuallyDrop<TcpStream> {
+    ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) })
+}
+
+#[derive(Default)]
+struct Interrupted {
+    /// Descriptor of the current connection
+    fd: Option<RawFd>,
+    interrupted: bool,
+}
+
+/// Shuts the connections of a client down from another thread, failing their blocked reads,
+/// e.g. to stop a long-polling request
+#[derive(Clone, Default)]
+pub struct Interrupt(Arc<Mutex<Interrupted>>);
+
+impl Interrupt {
+    /// Shuts the current connection down, and the ones registered later right away
+    pub fn interrupt(&self) {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        state.interrupted = true;
+        if let Some(fd) = state.fd {
+            let _ = stream(fd).shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .interrupted
+    }
+
+    fn register(&self, fd: RawFd) {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        state.fd = Some(fd);
+        if state.interrupted {
+            let _ = stream(fd).shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Forgets `fd` before it is closed, and maybe reused by another connection
+    fn unregister(&self, fd: RawFd) {
+        let mut state = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.fd == Some(fd) {
+            state.fd = None;
+        }
+    }
+}
+
+/// Connection of the lnsocket library, which it owns and destroys once dropped
+pub struct RawSocket {
+    ptr: *mut lnsocket::lnsocket,
+    /// Shuts the connection down from another thread
+    interrupt: Option<Interrupt>,
+}
+
+// the connection is only reached through the owned pointer, never shared with other threads
+unsafe impl Send for RawSocket {}
+
+fn c_string(name: &str, value: &str) -> Result<CString, Error> {
+    CString::new(value).map_err(|_| Error::InvalidParams(format!("nul byte in the {}", name)))
+}
+
+/// Runs `f` on a thread of its own and waits `timeout` for its result, `None` past it, the
+/// late result being dropped once there
+fn within<T, F>(timeout: Duration, f: F) -> Option<Result<T, Error>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // the receiver is gone once timed out
+        let _ = sender.send(f());
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => Some(result),
+        Err(RecvTimeoutError::Timeout) => None,
+        // the thread panicked
+        Err(RecvTimeoutError::Disconnected) => Some(Err(Error::ConnectionClosed)),
+    }
+}
+
+impl RawSocket {
+    /// Connects to the node `nodeid` at `host`, through the socks5 `proxy` if any, performing
+    /// the handshake and the init exchange within `timeout`, then failing the reads and writes
+    /// of the connection which take longer than `io_timeout`.
+    ///
+    /// The library can not time out while connecting, so it connects from a thread of its own,
+    /// given up on past `timeout`.
+    pub fn connect(
+        nodeid: &str,
+        host: &NodeAddr,
+        proxy: Option<&NodeAddr>,
+        timeout: Duration,
+        io_timeout: Duration,
+    ) -> Result<Self, Error> {
+        let c_node_id = c_string("node id", nodeid)?;
+        // onion hosts are only resolved by the proxy
+        let c_host = c_string("host", &host.to_string())?;
+        let c_proxy = match proxy {
+            Some(proxy) => Some(c_string("proxy", &proxy.to_string())?),
+            None => None,
+        };
+        let target = format!("{}@{}", nodeid, host);
+        let unreachable = target.clone();
+        let connecting = move || {
+            let ptr = unsafe { lnsocket::lnsocket_create() };
+            if ptr.is_null() {
+                return Err(Error::ConnectionClosed);
+            }
+            // destroys the connection on the failures below
+            let socket = RawSocket {
+                ptr,
+                interrupt: None,
+            };
+            let connected = unsafe {
+                lnsocket::lnsocket_genkey(socket.ptr);
+                match &c_proxy {
+                    Some(c_proxy) => lnsocket::lnsocket_connect_tor(
+                        socket.ptr,
+                        c_node_id.as_ptr(),
+                        c_host.as_ptr(),
+                        c_proxy.as_ptr(),
+                    ),
+                    None => {
+                        lnsocket::lnsocket_connect(socket.ptr, c_node_id.as_ptr(), c_host.as_ptr())
+                    }
+                }
+            };
+            if connected != 1 {
+                return Err(Error::Unreachable(unreachable));
+            }
+            socket.set_timeout(io_timeout)?;
+            if unsafe { lnsocket::lnsocket_perform_init(socket.ptr) } != 1 {
+                return Err(Error::ConnectionClosed);
+            }
+            Ok(socket)
+        };
+        within(timeout, connecting).unwrap_or_else(|| {
+            let e = format!("{}: timed out after {}s", target, timeout.as_secs());
+            Err(Error::Unreachable(e))
+        })
+    }
+
+    fn fd(&self) -> RawFd {
+        unsafe { (*self.ptr).socket }
+    }
+
+    /// Fails the reads and writes of the connection which take longer than `timeout`
+    fn set_timeout(&self, timeout: Duration) -> Result<(), Error> {
+        let stream = stream(self.fd());
+        stream
+            .set_read_timeout(Some(timeout))
+            .and_then(|_| stream.set_write_timeout(Some(timeout)))
+            .map_err(|e| Error::Unreachable(e.to_string()))
+    }
+
+    /// Lets `interrupt` shut the connection down
+    pub fn interrupt_with(&mut self, interrupt: &Interrupt) {
+        interrupt.register(self.fd());
+        self.interrupt = Some(interrupt.clone());
+    }
+}
+
+impl Transport for RawSocket {
+    fn write(&mut self, msg: &[u8]) -> Result<(), Error> {
+        if msg.len() > MAX_MESSAGE {
+            return Err(Error::MessageTooLong(msg.len()));
+        }
+        match unsafe { lnsocket::lnsocket_write(self.ptr, msg.as_ptr(), msg.len() as u16) } {
+            1 => Ok(()),
+            _ => Err(Error::ConnectionClosed),
+        }
+    }
+
+    fn recv(&mut self) -> Result<(u16, Vec<u8>), Error> {
+        let mut typ = 0u16;
+        let mut payload: *mut u8 = std::ptr::null_mut();
+        let mut len = 0u16;
+        if unsafe { lnsocket::lnsocket_recv(self.ptr, &mut typ, &mut payload, &mut len) } != 1 {
+            return Err(Error::ConnectionClosed);
+        }
+        // the payload lives in the buffer of the connection until the next read
+        let payload = match (payload.is_null(), len) {
+            (_, 0) => vec![],
+            (true, _) => return Err(Error::ConnectionClosed),
+            (false, len) => unsafe { slice::from_raw_parts(payload, len as usize) }.to_vec(),
+        };
+        Ok((typ, payload))
+    }
+}
+
+impl Drop for RawSocket {
+    fn drop(&mut self) {
+        if let Some(interrupt) = self.interrupt.take() {
+            interrupt.unregister(self.fd());
+        }
+        unsafe { lnsocket::lnsocket_destroy(self.ptr) }
+    }
+}
+
+/// Commando client over a lightning connection, matching the replies to the requests by id
+pub struct LnSocket<T: Transport = RawSocket> {
+    transport: T,
+    next_id: u64,
+    /// Ids of the requests waiting for their reply, the replies to the other ones are dropped
+    awaited: HashSet<u64>,
+    /// Chunks of the replies still being received, by request id
+    partial: HashMap<u64, Vec<u8>>,
+    /// Replies received while waiting for other ones, by request id
+    replies: HashMap<u64, Vec<u8>>,
+}
+
+impl LnSocket<RawSocket> {
+    /// Connects to the node `nodeid` at `host`, through the socks5 `proxy` if any, see
+    /// [`RawSocket::connect`]
+    pub fn connect(
+        nodeid: &str,
+        host: &NodeAddr,
+        proxy: Option<&NodeAddr>,
+        timeout: Duration,
+        io_timeout: Duration,
+    ) -> Result<Self, Error> {
+        Ok(LnSocket::new(RawSocket::connect(
+            nodeid, host, proxy, timeout, io_timeout,
+        )?))
+    }
+
+    /// Lets `interrupt` shut the connection down
+    pub fn interrupt_with(&mut self, interrupt: &Interrupt) {
+        self.transport.interrupt_with(interrupt)
+    }
+}
+
+impl<T: Transport> LnSocket<T> {
+    pub fn new(transport: T) -> Self {
+        LnSocket {
+            transport,
+            next_id: 1,
+            awaited: HashSet::new(),
+            partial: HashMap::new(),
+            replies: HashMap::new(),
+        }
+    }
+
+    /// Sends the json `command`, returning the id of its reply
+    pub fn send(&mut self, command: &str) -> Result<u64, Error> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut msg = Vec::with_capacity(10 + command.len());
+        msg.extend_from_slice(&COMMANDO_CMD.to_be_bytes());
+        msg.extend_from_slice(&id.to_be_bytes());
+        msg.extend_from_slice(command.as_bytes());
+        self.transport.write(&msg)?;
+        self.awaited.insert(id);
+        Ok(id)
+    }
+
+    /// Waits for the reply to the request `id`, keeping aside the replies to the other awaited
+    /// ones. The request is abandoned if it fails, e.g. timed out, its late reply dropped.
+    pub fn receive(&mut self, id: u64) -> Result<String, Error> {
+        let reply = self.wait(id);
+        self.awaited.remove(&id);
+        self.partial.remove(&id);
+        reply
+    }
+
+    fn wait(&mut self, id: u64) -> Result<String, Error> {
+        loop {
+            if let Some(reply) = self.replies.remove(&id) {
+                return String::from_utf8(reply).map_err(|e| Error::Json(e.to_string()));
+            }
+            let (typ, payload) = self.transport.recv()?;
+            match typ {
+                COMMANDO_REPLY_CONTINUES | COMMANDO_REPLY_TERM => {
+                    if payload.len() < 8 {
+                        return Err(Error::UnexpectedMessage(typ));
+                    }
+                    let mut reply_id = [0u8; 8];
+                    reply_id.copy_from_slice(&payload[..8]);
+                    let reply_id = u64::from_be_bytes(reply_id);
+                    if !self.awaited.contains(&reply_id) {
+                        continue;
+                    }
+                    let chunks = self.partial.entry(reply_id).or_default();
+                    chunks.extend_from_slice(&payload[8..]);
+                    if typ == COMMANDO_REPLY_TERM {
+                        let reply = self.partial.remove(&reply_id).unwrap_or_default();
+                        self.replies.insert(reply_id, reply);
+                    }
+                }
+                PING => self.pong(&payload)?,
+                // unknown odd messages can be ignored
+                typ if typ % 2 == 1 => {}
+                typ => return Err(Error::UnexpectedMessage(typ)),
+            }
+        }
+    }
+
+    /// Sends the json `command` and waits for its reply
+    pub fn call(&mut self, command: &str) -> Result<String, Error> {
+        let id = self.send(command)?;
+        self.receive(id)
+    }
+
+    /// Answers a ping with the number of bytes it asks for
+    fn pong(&mut self, ping: &[u8]) -> Result<(), Error> {
+        if ping.len() < 2 {
+            return Err(Error::UnexpectedMessage(PING));
+        }
+        let num_pong_bytes = u16::from_be_bytes([ping[0], ping[1]]) as usize;
+        // no pong is expected for the larger ones
+        if num_pong_bytes >= 65532 {
+            return Ok(());
+        }
+        let mut msg = Vec::with_capacity(4 + num_pong_bytes);
+        msg.extend_from_slice(&PONG.to_be_bytes());
+        msg.extend_from_slice(&(num_pong_bytes as u16).to_be_bytes());
+        msg.resize(4 + num_pong_bytes, 0);
+        self.transport.write(&msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use socket::{within, Backoff, Interrupt, LnSocket, NodeAddr, Transport};
+    use std::collections::VecDeque;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use Error;
+
+    /// Transport replaying canned messages, recording the written ones
+    #[derive(Default)]
+    struct Canned {
+        incoming: VecDeque<(u16, Vec<u8>)>,
+        written: Vec<Vec<u8>>,
+    }
+
+    impl Transport for Canned {
+        fn write(&mut self, msg: &[u8]) -> Result<(), Error> {
+            self.written.push(msg.to_vec());
+            Ok(())
+        }
+
+        fn recv(&mut self) -> Result<(u16, Vec<u8>), Error> {
+            self.incoming.pop_front().ok_or(Error::ConnectionClosed)
+        }
+    }
+
+    fn reply(typ: u16, id: u64, chunk: &str) -> (u16, Vec<u8>) {
+        let mut payload = id.to_be_bytes().to_vec();
+        payload.extend_from_slice(chunk.as_bytes());
+        (typ, payload)
+    }
+
+    fn socket(incoming: Vec<(u16, Vec<u8>)>) -> LnSocket<Canned> {
+        LnSocket::new(Canned {
+            incoming: incoming.into(),
+            written: vec![],
+        })
+    }
+
+    #[test]
+    fn call() {
+        let mut socket = socket(vec![
+            reply(0x594b, 1, "{\"result\":"),
+            reply(0x594b, 1, "{}"),
+            reply(0x594d, 1, "}"),
+        ]);
+        assert_eq!(
+            "{\"result\":{}}",
+            socket.call("{\"method\":\"getinfo\"}").unwrap()
+        );
+
+        let written = &socket.transport.written;
+        assert_eq!(1, written.len());
+        assert_eq!(&[0x4c, 0x4f, 0, 0, 0, 0, 0, 0, 0, 1], &written[0][..10]);
+        assert_eq!(b"{\"method\":\"getinfo\"}", &written[0][10..]);
+    }
+
+    #[test]
+    fn request_ids() {
+        let mut socket = socket(vec![
+            reply(0x594b, 2, "{\"second\""),
+            reply(0x594b, 1, "{\"first\""),
+            reply(0x594d, 2, ":2}"),
+            reply(0x594d, 1, ":1}"),
+        ]);
+        let first = socket.send("{}").unwrap();
+        let second = socket.send("{}").unwrap();
+        assert_eq!((1, 2), (first, second));
+        assert_eq!(
+            &[0, 0, 0, 0, 0, 0, 0, 2],
+            &socket.transport.written[1][2..10]
+        );
+        // the replies are sorted out whatever their order
+        assert_eq!("{\"second\":2}", socket.receive(second).unwrap());
+        assert_eq!("{\"first\":1}", socket.receive(first).unwrap());
+
+        // the reply to a request nobody awaits is dropped
+        let mut socket = self::socket(vec![reply(0x594d, 1, "late"), reply(0x594d, 2, "ok")]);
+        socket.next_id = 2;
+        assert_eq!("ok", socket.call("{}").unwrap());
+        assert!(socket.replies.is_empty());
+
+        // as the rest of a failed request
+        let mut socket = self::socket(vec![reply(0x594b, 1, "{")]);
+        assert!(socket.call("{}").is_err());
+        socket.transport.incoming = vec![reply(0x594d, 1, "}"), reply(0x594d, 2, "ok")].into();
+        assert_eq!("ok", socket.call("{}").unwrap());
+        assert!(socket.awaited.is_empty());
+        assert!(socket.partial.is_empty());
+        assert!(socket.replies.is_empty());
+    }
+
+    #[test]
+    fn ping() {
+        let mut socket = socket(vec![
+            (18, vec![0, 3, 0, 1, 0xff]),
+            (18, vec![0xff, 0xff, 0, 0]),
+            reply(0x594d, 1, "{}"),
+        ]);
+        assert_eq!("{}", socket.call("{}").unwrap());
+        let written = &socket.transport.written;
+        assert_eq!(2, written.len());
+        assert_eq!(&vec![0, 19, 0, 3, 0, 0, 0], &written[1]);
+    }
+
+    #[test]
+    fn unexpected_messages() {
+        // odd messages are ignored, even ones are not
+        let mut socket = socket(vec![(33, vec![1, 2]), reply(0x594d, 1, "{}")]);
+        assert_eq!("{}", socket.call("{}").unwrap());
+
+        let mut socket = self::socket(vec![(16, vec![])]);
+        assert!(matches!(
+            socket.call("{}"),
+            Err(Error::UnexpectedMessage(16))
+        ));
+
+        let mut socket = self::socket(vec![(0x594d, vec![0, 0, 1])]);
+        assert!(matches!(
+            socket.call("{}"),
+            Err(Error::UnexpectedMessage(0x594d))
+        ));
+
+        let mut socket = self::socket(vec![(0x594d, vec![0, 0, 0, 0, 0, 0, 0, 1, 0xc3])]);
+        assert!(matches!(socket.call("{}"), Err(Error::Json(_))));
+
+        let mut socket = self::socket(vec![reply(0x594b, 1, "{")]);
+        assert!(matches!(socket.call("{}"), Err(Error::ConnectionClosed)));
+    }
+
+    #[test]
+    fn node_addr() {
+        let addr = |host: &str, port| NodeAddr {
+            host: host.to_string(),
+            port,
+        };
+        assert_eq!(addr("10.0.0.1", 9735), "10.0.0.1".parse().unwrap());
+        assert_eq!(addr("10.0.0.1", 19735), "10.0.0.1:19735".parse().unwrap());
+        assert_eq!(addr("node.lan", 9735), " node.lan ".parse().unwrap());
+        assert_eq!(addr("::1", 9735), "::1".parse().unwrap());
+        assert_eq!(addr("::1", 9735), "[::1]".parse().unwrap());
+        assert_eq!(addr("fe80::1", 19735), "[fe80::1]:19735".parse().unwrap());
+        assert_eq!(
+            addr("abc.onion", 9050),
+            NodeAddr::parse("abc.onion", 9050).unwrap()
+        );
+        for invalid in &["", ":9735", "host:port", "host:99999", "[::1", "[::1]9735"] {
+            assert!(invalid.parse::<NodeAddr>().is_err(), "{}", invalid);
+        }
+
+        assert_eq!("10.0.0.1:9735", addr("10.0.0.1", 9735).to_string());
+        assert_eq!("[::1]:9735", addr("::1", 9735).to_string());
+    }
+
+    #[test]
+    fn timeout() {
+        let quick = within(Duration::from_secs(5), || Ok(1));
+        assert!(matches!(quick, Some(Ok(1))));
+        let failed = within(Duration::from_secs(5), || {
+            Err::<(), _>(Error::ConnectionClosed)
+        });
+        assert!(matches!(failed, Some(Err(Error::ConnectionClosed))));
+
+        let hung = within(Duration::from_millis(10), || {
+            thread::sleep(Duration::from_secs(1));
+            Ok(())
+        });
+        assert!(hung.is_none());
+        let panicked = within(Duration::from_secs(5), || -> Result<(), Error> { panic!() });
+        assert!(matches!(panicked, Some(Err(Error::ConnectionClosed))));
+    }
+
+    #[test]
+    fn interrupt() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let interrupt = Interrupt::default();
+        interrupt.register(stream.as_raw_fd());
+        let blocked = thread::spawn(move || stream.read(&mut [0u8; 1]).unwrap());
+        interrupt.interrupt();
+        assert!(interrupt.is_interrupted());
+        assert_eq!(0, blocked.join().unwrap());
+
+        // the connections registered once interrupted are shut down right away
+        let mut late = TcpStream::connect(addr).unwrap();
+        interrupt.register(late.as_raw_fd());
+        assert_eq!(0, late.read(&mut [0u8; 1]).unwrap());
+        interrupt.unregister(late.as_raw_fd());
+    }
+
+    #[test]
+    fn backoff() {
+        let now = Instant::now();
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        assert_eq!(None, backoff.wait(now));
+        backoff.fail(now);
+        assert_eq!(Some(Duration::from_secs(1)), backoff.wait(now));
+        assert_eq!(None, backoff.wait(now + Duration::from_secs(1)));
+        backoff.fail(now);
+        assert_eq!(Some(Duration::from_secs(2)), backoff.wait(now));
+        backoff.fail(now);
+        assert_eq!(Some(Duration::from_secs(4)), backoff.wait(now));
+        backoff.fail(now);
+        assert_eq!(Some(Duration::from_secs(5)), backoff.wait(now));
+        for _ in 0..100 {
+            backoff.fail(now);
+        }
+        assert_eq!(Some(Duration::from_secs(5)), backoff.wait(now));
+        backoff.reset();
+        assert_eq!(None, backoff.wait(now));
+    }
+}
@@ -1,11 +1,8 @@
-use edk::bdk::Error;
+use crate::Error;
+use log::debug;
 use serde_derive::Deserialize;
 use std::collections::HashMap;
 
-pub fn gen_err() -> Error {
-    Error::Generic(format!("oh no!"))
-}
-
 #[derive(Deserialize, Clone, Debug)]
 pub struct Asset {
     pub asset_id: String,
@@ -28,9 +25,9 @@ impl EsploraRepository {
 
     pub fn fetch(&mut self, asset_id: String) -> Result<Asset, Error> {
         let url = format!("https://blockstream.info/liquid/api/asset/{}", asset_id);
-        let res = reqwest::blocking::get(url).map_err(|_| gen_err())?;
-        let asset: Asset = res.json().map_err(|_| gen_err())?;
-        println!("Asset: {:#?}", asset);
+        let res = reqwest::blocking::get(url).map_err(Error::Esplora)?;
+        let asset: Asset = res.json().map_err(Error::Esplora)?;
+        debug!("fetched asset {} ({})", asset.name, asset_id);
         self.assets.insert(asset_id, asset.clone());
         Ok(asset)
     }
@@ -6,58 +6,156 @@ extern crate reqwest;
 extern crate structopt;
 
 use crate::config::LiquidOpts;
-use edk::bdk::electrum_client::Client;
-use edk::bdk::Error;
+use btctipserver_backend::invoice::InvoiceStore;
+use btctipserver_backend::Error as BackendError;
+use btctipserver_backend::{InvoiceParams, Payment, PaymentBackend};
+use edk::bdk::bitcoin::{Script, Txid};
+use edk::bdk::electrum_client::{self, Client, ElectrumApi};
 use std::collections::HashMap;
+use std::fmt;
 use std::ops::Div;
 use std::str::FromStr;
 
 use edk::bdk::sled::{self, Tree};
 use edk::miniscript::elements::secp256k1_zkp;
 use edk::miniscript::elements::slip77::MasterBlindingKey;
-use edk::miniscript::elements::Address;
+use edk::miniscript::elements::{encode, Address, AddressError, Transaction};
 use edk::miniscript::{Descriptor, DescriptorPublicKey};
 use esplora::EsploraRepository;
 use std::fs;
 use std::path::PathBuf;
 
+/// Asset id of L-BTC on liquid
+const LIQUID_POLICY_ASSET: &str =
+    "6f0279e9ed041c3d710a9f57d0c02928416460c4b722ae3457a11eec381c526d";
+/// Asset id of the default policy asset on elements regtest
+const ELEMENTS_POLICY_ASSET: &str =
+    "5ac9f65c0efcc4775e0baec4ec03abdde22473cd3cf33c0419ca290e0751b225";
+
 pub struct LiquidWallet {
     wallet: edk::Wallet<Tree>,
+    /// Reads the history and the confirmations of the addresses
+    client: Client,
     esplora: EsploraRepository,
+    invoices: InvoiceStore,
+}
+
+/// Errors that can be thrown by the [`LiquidWallet`](crate::LiquidWallet)
+#[derive(Debug)]
+pub enum Error {
+    /// The descriptor or the master blinding key are malformed
+    Config(String),
+    /// The edk wallet failed
+    Wallet(edk::Error),
+    /// The electrum server is unreachable
+    Electrum(String),
+    /// The address is malformed
+    Address(AddressError),
+    /// The esplora asset registry failed
+    Esplora(reqwest::Error),
+    /// The datadir or the database are unusable
+    Database(String),
+    /// The invoice store failed
+    Backend(BackendError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(e) => write!(f, "invalid configuration: {}", e),
+            // edk errors only implement Debug
+            Error::Wallet(e) => write!(f, "wallet: {:?}", e),
+            Error::Electrum(e) => write!(f, "electrum: {}", e),
+            Error::Address(e) => write!(f, "{}", e),
+            Error::Esplora(e) => write!(f, "esplora: {}", e),
+            Error::Database(e) => write!(f, "database: {}", e),
+            Error::Backend(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<AddressError> for Error {
+    fn from(e: AddressError) -> Self {
+        Error::Address(e)
+    }
+}
+
+impl From<electrum_client::Error> for Error {
+    fn from(e: electrum_client::Error) -> Self {
+        Error::Electrum(e.to_string())
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Database(e.to_string())
+    }
+}
+
+impl From<BackendError> for Error {
+    fn from(e: BackendError) -> Self {
+        Error::Backend(e)
+    }
+}
+
+impl From<Error> for BackendError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Config(e) => BackendError::Config(e.into()),
+            Error::Electrum(e) => BackendError::Connection(e.into()),
+            Error::Esplora(e) => BackendError::Connection(Box::new(e)),
+            Error::Address(e) => BackendError::InvalidAddress(Box::new(e)),
+            // edk errors only implement Debug
+            Error::Wallet(_) => BackendError::Backend(e.to_string().into()),
+            Error::Database(e) => BackendError::Database(e.into()),
+            Error::Backend(e) => e,
+        }
+    }
 }
 
-pub fn gen_err() -> Error {
-    Error::Generic(format!("oh no!"))
+impl From<edk::Error> for Error {
+    fn from(e: edk::Error) -> Self {
+        Error::Wallet(e)
+    }
 }
 
 impl LiquidWallet {
-    pub fn prepare_home_dir(datadir: &str) -> PathBuf {
+    pub fn prepare_home_dir(datadir: &str) -> Result<PathBuf, Error> {
         let mut dir = PathBuf::new();
-        dir.push(&dirs_next::home_dir().unwrap());
+        let home = dirs_next::home_dir()
+            .ok_or_else(|| Error::Database("no home directory".to_string()))?;
+        dir.push(&home);
         dir.push(datadir);
 
         if !dir.exists() {
             //info!("Creating home directory {}", dir.as_path().display());
-            fs::create_dir(&dir).unwrap();
+            fs::create_dir(&dir)
+                .map_err(|e| Error::Database(format!("{}: {}", dir.display(), e)))?;
         }
 
         dir.push("database.sled");
-        dir
+        Ok(dir)
     }
 
     pub fn new(opts: &LiquidOpts) -> Result<Self, Error> {
         // setup database
-        let database = sled::open(Self::prepare_home_dir(&opts.data_dir).to_str().unwrap())?;
+        let database = sled::open(Self::prepare_home_dir(&opts.data_dir)?)?;
         let tree = database.open_tree(&opts.wallet)?;
+        let invoices = InvoiceStore::open(&database, &opts.wallet)?;
 
         // setup electrum blockchain client
-        let client = Client::new(&opts.electrum_opts.electrum).unwrap();
+        let client = Client::new(&opts.electrum_opts.electrum)?;
 
         // setup keys variables
-        let descriptor = Descriptor::<DescriptorPublicKey>::from_str(&opts.descriptor).unwrap();
-        let decoded: &[u8] = &hex::decode(&opts.master_blinding_key.as_str()).unwrap();
-        let master_blinding_key =
-            MasterBlindingKey(secp256k1_zkp::SecretKey::from_slice(decoded).unwrap());
+        let descriptor = Descriptor::<DescriptorPublicKey>::from_str(&opts.descriptor)
+            .map_err(|e| Error::Config(format!("descriptor: {}", e)))?;
+        let decoded = hex::decode(&opts.master_blinding_key.as_str())
+            .map_err(|e| Error::Config(format!("master blinding key: {}", e)))?;
+        let secret_key = secp256k1_zkp::SecretKey::from_slice(&decoded)
+            .map_err(|e| Error::Config(format!("master blinding key: {}", e)))?;
+        let master_blinding_key = MasterBlindingKey(secret_key);
 
         // create wallet shared by all requests
         let wallet = edk::Wallet::new(
@@ -66,39 +164,107 @@ impl LiquidWallet {
             tree,
             client,
             opts.network(),
-        )
-        .unwrap();
+        )?;
         Ok(LiquidWallet {
             wallet,
+            client: Client::new(&opts.electrum_opts.electrum)?,
             esplora: EsploraRepository {
                 assets: HashMap::new(),
             },
+            invoices,
         })
     }
+
+    fn policy_asset(&self) -> &'static str {
+        match self.wallet.network() {
+            &edk::miniscript::elements::AddressParams::LIQUID => LIQUID_POLICY_ASSET,
+            _ => ELEMENTS_POLICY_ASSET,
+        }
+    }
+
+    /// Returns the payments of the policy asset received by `addr` in its whole history, spent
+    /// outputs included, one per transaction with its confirmations.
+    fn history(&self, addr: &Address) -> Result<Vec<Payment>, Error> {
+        let policy_asset = self.policy_asset();
+        let mut amounts: HashMap<String, u64> = HashMap::new();
+        for (outpoint, tx_out) in self.wallet.balance_addresses(vec![addr.clone()])?.unblinds {
+            if tx_out.asset.to_string() == policy_asset {
+                *amounts.entry(outpoint.txid.to_string()).or_insert(0) += tx_out.value;
+            }
+        }
+        if amounts.is_empty() {
+            return Ok(vec![]);
+        }
+        // liquid headers are not bitcoin ones, only their height is read
+        let tip = self.client.block_headers_subscribe_raw()?.height;
+        let script = Script::from(addr.script_pubkey().into_bytes());
+        let mut payments = vec![];
+        for item in self.client.script_get_history(&script)? {
+            let txid = item.tx_hash.to_string();
+            let amount = match amounts.remove(&txid) {
+                Some(amount) => amount,
+                None => continue,
+            };
+            // unconfirmed transactions have height 0, or -1 with unconfirmed parents
+            let confirmations = match item.height {
+                height if height <= 0 => 0,
+                height => (tip + 1).saturating_sub(height as usize) as u32,
+            };
+            payments.push(Payment {
+                txid: Some(txid),
+                payment_hash: None,
+                amount,
+                confirmations: Some(confirmations),
+                // only unconfirmed transactions can be replaced
+                rbf: confirmations == 0 && self.signals_rbf(&item.tx_hash)?,
+                paid_at: None,
+            });
+        }
+        // unblinded before the server saw them in the history, still unconfirmed
+        for (txid, amount) in amounts {
+            payments.push(Payment {
+                txid: Some(txid),
+                payment_hash: None,
+                amount,
+                confirmations: Some(0),
+                rbf: false,
+                paid_at: None,
+            });
+        }
+        Ok(payments)
+    }
+
+    /// Checks if the transaction `txid` signals replace-by-fee (BIP125)
+    fn signals_rbf(&self, txid: &Txid) -> Result<bool, Error> {
+        let raw = self.client.transaction_get_raw(txid)?;
+        let tx: Transaction = encode::deserialize(&raw)
+            .map_err(|e| Error::Electrum(format!("malformed transaction {}: {}", txid, e)))?;
+        Ok(tx.input.iter().any(|input| input.sequence < 0xfffffffe))
+    }
 }
 
-impl LiquidWallet {
-    pub fn last_unused_address(&mut self) -> Result<String, Error> {
-        let address = self.wallet.get_new_address().map_err(|_| gen_err())?;
+impl PaymentBackend for LiquidWallet {
+    fn last_unused_address(&mut self, _params: &InvoiceParams) -> Result<String, BackendError> {
+        let address = self.wallet.get_new_address().map_err(Error::from)?;
         Ok(address.to_string())
     }
 
-    pub fn is_my_address(&mut self, addr: &str) -> Result<bool, Error> {
-        let address = Address::from_str(addr).map_err(|_| gen_err())?;
-        self.wallet.is_mine_address(&address).map_err(|_| gen_err())
+    fn is_my_address(&mut self, addr: &str) -> Result<bool, BackendError> {
+        let address = Address::from_str(addr).map_err(Error::from)?;
+        Ok(self.wallet.is_mine_address(&address).map_err(Error::from)?)
     }
 
-    pub fn balance_address(
+    fn balance_address(
         &mut self,
         addr: &str,
         _from_height: Option<usize>,
-    ) -> Result<HashMap<String, String>, Error> {
-        let addr = Address::from_str(addr).map_err(|_| gen_err())?;
+    ) -> Result<HashMap<String, String>, BackendError> {
+        let addr = Address::from_str(addr).map_err(Error::from)?;
         let mut balances = HashMap::new();
         for unblind in self
             .wallet
             .balance_addresses(vec![addr])
-            .map_err(|_| gen_err())?
+            .map_err(Error::from)?
             .unblinds
         {
             let tx_out = unblind.1;
@@ -121,10 +287,29 @@ impl LiquidWallet {
         Ok(res)
     }
 
-    pub fn network(&mut self) -> Result<String, Error> {
+    /// Returns the outputs of the policy asset unblinded from the address history, spent or
+    /// not, grouped by transaction
+    fn payments(&mut self, addr: &str) -> Result<Vec<Payment>, BackendError> {
+        let addr = Address::from_str(addr).map_err(Error::from)?;
+        Ok(self.history(&addr)?)
+    }
+
+    fn network(&mut self) -> Result<String, BackendError> {
         match self.wallet.network() {
             &edk::miniscript::elements::AddressParams::LIQUID => Ok("liquid".to_string()),
             _ => Ok("elements".to_string()),
         }
     }
+
+    fn schema(&self) -> &str {
+        "liquidnetwork"
+    }
+
+    fn currency(&self) -> &str {
+        "L-BTC"
+    }
+
+    fn invoices(&self) -> InvoiceStore {
+        self.invoices.clone()
+    }
 }
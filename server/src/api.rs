@@ -0,0 +1,294 @@
+use btctipserver_backend::invoice::{FiatPrice, Invoice, InvoiceState};
+use btctipserver_bitcoin::bdk::bitcoin::{Address, Amount};
+use btctipserver_bitcoin::bip21::Bip21;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use tiny_http::Method;
+
+use crate::config::ServerOpts;
+use crate::error::Error;
+use crate::form;
+use crate::invoices::{self, InvoiceRequest};
+use crate::rail::Rail;
+use crate::rates::Rates;
+use crate::store::{Store, Stores};
+
+const INVOICES_PATH: &str = "/api/v1/invoices/";
+
+/// Error returned to the API clients as `{"error": {"code": .., "message": ..}}`
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: &str) -> Self {
+        ApiError {
+            status,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        json!({ "error": { "code": self.status, "message": self.message } }).to_string()
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        if e.status() >= 500 {
+            warn!("api request failed: {}", e);
+        }
+        ApiError::new(e.status(), &e.message())
+    }
+}
+
+/// Body of `POST /api/v1/invoices`, every field is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CreateInvoice {
+    /// Requested amount in sats
+    pub amount: Option<u64>,
+    /// Requested price in `currency`, converted to the amount at the current rate
+    pub price: Option<f64>,
+    /// Fiat currency of the price, the store one if missing
+    pub currency: Option<String>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+    /// Confirmations needed to settle the invoice
+    pub confirmations: Option<u32>,
+    /// Schema of the rail issuing the invoice, the default one if missing
+    pub rail: Option<String>,
+    /// Name of the store issuing the invoice, the default one if missing
+    pub store: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InvoiceResponse {
+    pub id: String,
+    /// Name of the store which issued the invoice, empty for the default one
+    pub store: String,
+    pub backend: String,
+    /// On-chain address or bolt11 invoice
+    pub address: String,
+    /// Bolt11 invoice offered next to the on-chain address
+    pub lightning: Option<String>,
+    /// Payment URI, BIP21 for bitcoin
+    pub uri: String,
+    /// Requested amount in sats
+    pub amount: Option<u64>,
+    pub currency: String,
+    /// Fiat price the amount was converted from, with its locked rate
+    pub fiat: Option<FiatPrice>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+    pub state: InvoiceState,
+    /// Human readable state, e.g. `confirming (1/6)`
+    pub status: String,
+    /// Received amount in sats
+    pub received: u64,
+    /// Confirmations of the least confirmed payment
+    pub confirmations: Option<u32>,
+    pub required_confirmations: u32,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub network: String,
+    /// Received amounts keyed by asset, `btc` in sats on-chain and on lightning
+    pub balances: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RailInfo {
+    pub network: String,
+    pub schema: String,
+    pub currency: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InfoResponse {
+    /// Network of the default rail
+    pub network: String,
+    /// Schema of the default rail
+    pub schema: String,
+    /// Rails of the default store
+    pub rails: Vec<RailInfo>,
+    /// Names of the other stores
+    pub stores: Vec<String>,
+}
+
+/// Handles a request to the JSON API, returns the status code and the json body
+pub fn handle(
+    stores: &Stores,
+    rates: &Rates,
+    opts: &ServerOpts,
+    method: &Method,
+    path: &str,
+    body: &str,
+) -> (u16, String) {
+    let result = match (method, path.trim_end_matches('/')) {
+        (&Method::Get, "/api/v1/info") => info(stores).and_then(|res| to_json(200, &res)),
+        (&Method::Post, "/api/v1/invoices") => {
+            create_invoice(stores, rates, opts, body).and_then(|res| to_json(201, &res))
+        }
+        (&Method::Get, path) if path.starts_with(INVOICES_PATH) => {
+            get_invoice(stores, &path[INVOICES_PATH.len()..]).and_then(|res| to_json(200, &res))
+        }
+        (_, _) => Err(ApiError::new(404, "resource not found")),
+    };
+    match result {
+        Ok(res) => res,
+        Err(e) => (e.status, e.to_json()),
+    }
+}
+
+fn to_json<T: Serialize>(status: u16, value: &T) -> Result<(u16, String), ApiError> {
+    let json = serde_json::to_string(value)
+        .map_err(|_| ApiError::new(500, "unable to serialize the response"))?;
+    Ok((status, json))
+}
+
+fn info(stores: &Stores) -> Result<InfoResponse, ApiError> {
+    let rails = &stores.default_store().rails;
+    let rail = rails.default_rail();
+    Ok(InfoResponse {
+        network: rail.network(),
+        schema: rail.schema.clone(),
+        rails: rails
+            .iter()
+            .map(|rail| RailInfo {
+                network: rail.network(),
+                schema: rail.schema.clone(),
+                currency: rail.currency.clone(),
+            })
+            .collect(),
+        stores: stores
+            .iter()
+            .skip(1)
+            .map(|store| store.name.clone())
+            .collect(),
+    })
+}
+
+fn create_invoice(
+    stores: &Stores,
+    rates: &Rates,
+    opts: &ServerOpts,
+    body: &str,
+) -> Result<InvoiceResponse, ApiError> {
+    let req: CreateInvoice = match body.trim().is_empty() {
+        true => CreateInvoice::default(),
+        false => serde_json::from_str(body)
+            .map_err(|e| ApiError::new(400, format!("invalid request: {}", e).as_str()))?,
+    };
+    let store = match &req.store {
+        None => stores.default_store(),
+        Some(name) => stores
+            .get(name)
+            .ok_or_else(|| ApiError::new(400, format!("unknown store {}", name).as_str()))?,
+    };
+    let rail = match &req.rail {
+        None => store.rails.default_rail(),
+        Some(schema) => store
+            .rails
+            .get(schema)
+            .ok_or_else(|| ApiError::new(400, format!("unknown rail {}", schema).as_str()))?,
+    };
+    // the same bounds as the amount form
+    let amount = req.amount.map(form::check_amount).transpose()?;
+    let price = req.price.map(form::check_price).transpose()?;
+    let fiat = match (amount, price, &req.currency) {
+        (Some(_), Some(_), _) => {
+            return Err(ApiError::new(400, "invalid request: both amount and price"))
+        }
+        (_, Some(price), Some(currency)) => Some(rates.quote(currency, price)?),
+        (_, Some(price), None) => Some(store.quote(price, rates)?),
+        (_, None, _) => None,
+    };
+    let req = InvoiceRequest {
+        amount,
+        label: req.label,
+        message: req.message,
+        confirmations: req.confirmations,
+        ..Default::default()
+    };
+    let req = match fiat {
+        Some(fiat) => req.priced(fiat),
+        None => req,
+    };
+    let req = store.complete(req, rates)?;
+    let invoice = invoices::create(rail, req, opts)?;
+    Ok(invoice_response(store, rail, invoice))
+}
+
+fn get_invoice(stores: &Stores, id: &str) -> Result<InvoiceResponse, ApiError> {
+    let (store, rail, invoice) = stores
+        .find(id)?
+        .ok_or_else(|| ApiError::new(404, "invoice not found"))?;
+    Ok(invoice_response(store, rail, invoice))
+}
+
+fn invoice_response(store: &Store, rail: &Rail, invoice: Invoice) -> InvoiceResponse {
+    let uri = payment_uri(
+        &rail.schema,
+        &invoice.address,
+        invoice.amount,
+        &invoice.label,
+        &invoice.message,
+        &invoice.lightning,
+    );
+    InvoiceResponse {
+        status: invoice.status(),
+        balances: rail.balances(&invoice.address),
+        network: rail.network(),
+        id: invoice.id,
+        store: store.name.clone(),
+        backend: invoice.backend,
+        address: invoice.address,
+        lightning: invoice.lightning,
+        uri,
+        amount: invoice.amount,
+        currency: invoice.currency,
+        fiat: invoice.fiat,
+        label: invoice.label,
+        message: invoice.message,
+        state: invoice.state,
+        received: invoice.received,
+        confirmations: invoice.confirmations,
+        required_confirmations: invoice.required_confirmations,
+        created_at: invoice.created_at,
+        expires_at: invoice.expires_at,
+    }
+}
+
+/// Builds the payment link, a BIP21 uri for bitcoin addresses (offering the `lightning` invoice
+/// too, if any), `<schema>:<address>` otherwise
+pub fn payment_uri(
+    schema: &str,
+    address: &str,
+    amount: Option<u64>,
+    label: &Option<String>,
+    message: &Option<String>,
+    lightning: &Option<String>,
+) -> String {
+    if schema == "bitcoin" {
+        if let Ok(address) = Address::from_str(address) {
+            let bip21 = Bip21 {
+                scheme: schema.to_string(),
+                address,
+                amount: amount.map(Amount::from_sat),
+                label: label.clone(),
+                message: message.clone(),
+                lightning: lightning.clone(),
+                extras: BTreeMap::new(),
+            };
+            if let Ok(uri) = bip21.as_str() {
+                return uri;
+            }
+        }
+    }
+    format!("{}:{}", schema, address)
+}
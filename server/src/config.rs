@@ -1,4 +1,7 @@
-use ini::Ini;
+use btctipserver_bitcoin::config::BitcoinOpts;
+use btctipserver_lightning::config::ClightningOpts;
+use btctipserver_liquid::config::LiquidOpts;
+use ini::{Ini, Properties};
 use std::env;
 use structopt::StructOpt;
 
@@ -38,21 +41,207 @@ pub struct ConfigOpts {
         default_value = "config.ini"
     )]
     pub config: String,
+    #[structopt(flatten)]
+    pub server_opts: ServerOpts,
+    /// Platform to serve, all the ones declared in the config file sections if missing
     #[structopt(subcommand)]
-    pub cmd: Platforms,
+    pub cmd: Option<Platforms>,
+}
+
+/// Server options shared by all the platforms
+#[derive(Debug, StructOpt, Clone, PartialEq)]
+pub struct ServerOpts {
+    /// Invoice expiry in seconds
+    #[structopt(
+        name = "EXPIRY",
+        env = "EXPIRY",
+        long = "expiry",
+        default_value = "86400"
+    )]
+    pub expiry: u64,
+    /// Confirmations needed to settle an invoice, 0 to accept payments in the mempool
+    #[structopt(
+        name = "CONFIRMATIONS",
+        env = "CONFIRMATIONS",
+        long = "confirmations",
+        default_value = "1"
+    )]
+    pub confirmations: u32,
+    /// Refuse to settle with unconfirmed transactions signalling replace-by-fee
+    #[structopt(
+        name = "REFUSE_RBF",
+        env = "REFUSE_RBF",
+        long = "refuse-rbf",
+        parse(try_from_str),
+        default_value = "false"
+    )]
+    pub refuse_rbf: bool,
+    /// Percentage of the requested amount an invoice can be under or overpaid and still be paid
+    #[structopt(
+        name = "PAYMENT_TOLERANCE",
+        env = "PAYMENT_TOLERANCE",
+        long = "payment-tolerance",
+        default_value = "0"
+    )]
+    pub payment_tolerance: f64,
+    /// Seconds between two syncs of the backend, which also happen on new blocks
+    #[structopt(
+        name = "SYNC_INTERVAL",
+        env = "SYNC_INTERVAL",
+        long = "sync-interval",
+        default_value = "30"
+    )]
+    pub sync_interval: u64,
+    /// Threads serving the HTTP requests
+    #[structopt(
+        name = "WORKERS",
+        env = "WORKERS",
+        long = "workers",
+        default_value = "8"
+    )]
+    pub workers: usize,
+    /// Largest number of payment pages following their invoice over a websocket, the others
+    /// are refused
+    #[structopt(
+        name = "MAX_WEBSOCKETS",
+        env = "MAX_WEBSOCKETS",
+        long = "max-websockets",
+        default_value = "100"
+    )]
+    pub max_websockets: usize,
+    /// Seconds a request waits for a busy backend before failing
+    #[structopt(
+        name = "REQUEST_TIMEOUT",
+        env = "REQUEST_TIMEOUT",
+        long = "request-timeout",
+        default_value = "30"
+    )]
+    pub request_timeout: u64,
+    /// Fixed exchange rates, the price of a bitcoin in each fiat currency, e.g. `EUR=25000`
+    #[structopt(
+        name = "RATE",
+        env = "RATES",
+        long = "rate",
+        number_of_values = 1,
+        use_delimiter = true
+    )]
+    pub rates: Vec<String>,
+    /// JSON file mapping the fiat currencies to the price of a bitcoin, read on every request
+    #[structopt(name = "RATES_FILE", env = "RATES_FILE", long = "rates-file")]
+    pub rates_file: Option<String>,
+    /// Url of a JSON exchange rate source, `{currency}` is replaced by the lowercase currency
+    /// code and `{CURRENCY}` by the uppercase one
+    #[structopt(name = "RATES_URL", env = "RATES_URL", long = "rates-url")]
+    pub rates_url: Option<String>,
+    /// JSON pointer to the rate in the answers of the rates url, with the same placeholders
+    #[structopt(
+        name = "RATES_POINTER",
+        env = "RATES_POINTER",
+        long = "rates-pointer",
+        default_value = "/bitcoin/{currency}"
+    )]
+    pub rates_pointer: String,
+    /// Seconds a rate fetched from the rates url is reused
+    #[structopt(
+        name = "RATES_TTL",
+        env = "RATES_TTL",
+        long = "rates-ttl",
+        default_value = "60"
+    )]
+    pub rates_ttl: u64,
+    /// Webhook urls notified of the invoice state changes
+    #[structopt(
+        name = "WEBHOOK_URL",
+        env = "WEBHOOKS",
+        long = "webhook",
+        number_of_values = 1,
+        use_delimiter = true
+    )]
+    pub webhooks: Vec<String>,
+    /// Secret used to sign the webhook notifications
+    #[structopt(
+        name = "WEBHOOK_SECRET",
+        env = "WEBHOOK_SECRET",
+        long = "webhook-secret"
+    )]
+    pub webhook_secret: Option<String>,
+    /// Delivery retries of a webhook notification
+    #[structopt(
+        name = "WEBHOOK_RETRIES",
+        env = "WEBHOOK_RETRIES",
+        long = "webhook-retries",
+        default_value = "5"
+    )]
+    pub webhook_retries: u32,
+    /// Public url of the server, e.g. `https://tips.example.com`, enabling LNURL-pay
+    #[structopt(name = "PUBLIC_URL", env = "PUBLIC_URL", long = "public-url")]
+    pub public_url: Option<String>,
+    /// Smallest amount in sats a payer can send through LNURL-pay
+    #[structopt(
+        name = "LNURL_MIN",
+        env = "LNURL_MIN",
+        long = "lnurl-min",
+        default_value = "1"
+    )]
+    pub lnurl_min: u64,
+    /// Largest amount in sats a payer can send through LNURL-pay
+    #[structopt(
+        name = "LNURL_MAX",
+        env = "LNURL_MAX",
+        long = "lnurl-max",
+        default_value = "1000000"
+    )]
+    pub lnurl_max: u64,
+    /// Longest comment a payer can attach to a LNURL payment, 0 to refuse them
+    #[structopt(
+        name = "LNURL_COMMENT",
+        env = "LNURL_COMMENT",
+        long = "lnurl-comment",
+        default_value = "0"
+    )]
+    pub lnurl_comment: u32,
+    /// Lightning Addresses served at the domain of the public url, as `<username>=<store>`,
+    /// e.g. `alice=alice`, or `<username>` for the default store
+    #[structopt(
+        name = "LIGHTNING_ADDRESS",
+        env = "LIGHTNING_ADDRESSES",
+        long = "lightning-address",
+        number_of_values = 1,
+        use_delimiter = true
+    )]
+    pub lightning_addresses: Vec<String>,
+    /// Server data dir, keeping the webhook delivery log
+    #[structopt(
+        name = "SERVER_DATADIR",
+        env = "SERVER_DATADIR",
+        long = "server-datadir",
+        default_value = ".btctipserver"
+    )]
+    pub data_dir: String,
 }
 
 #[derive(Debug, Clone, PartialEq, StructOpt)]
 pub enum Platforms {
     #[structopt(name = "bitcoin", about = "use for bitcoin")]
-    Bitcoin(btctipserver_bitcoin::config::BitcoinOpts),
+    Bitcoin(BitcoinOpts),
     #[structopt(name = "liquid", about = "use for liquid")]
-    Liquid(btctipserver_liquid::config::LiquidOpts),
+    Liquid(LiquidOpts),
     #[structopt(name = "clightning", about = "use for clightning with commando plugin")]
-    CLightning(btctipserver_lightning::config::ClightningOpts),
+    CLightning(ClightningOpts),
+}
+
+impl Platforms {
+    /// Name of the platform, as subcommand and config file section
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platforms::Bitcoin(_) => "bitcoin",
+            Platforms::Liquid(_) => "liquid",
+            Platforms::CLightning(_) => "clightning",
+        }
+    }
 }
 
-pub(crate) fn load_ini_to_env(ini: Ini) {
+pub fn load_ini_to_env(ini: &Ini) {
     // load config from ini file (if it exists) into process env
     if let Some(section_bdk) = ini.section(None::<String>) {
         for (k, v) in section_bdk.iter() {
@@ -64,21 +253,178 @@ pub(crate) fn load_ini_to_env(ini: Ini) {
     }
 }
 
+/// Turns the `key = value` pairs of an ini section into `--key value` arguments
+fn section_args(section: &Properties) -> Vec<String> {
+    let mut args = vec!["btctipserver".to_string()];
+    for (k, v) in section.iter() {
+        args.push(format!("--{}", k));
+        args.push(v.to_string());
+    }
+    args
+}
+
+/// Parses the options of an ini section from its own keys only, failing on the missing
+/// required ones: the env holds the server options and the top-level section, which share
+/// option names with the platforms, e.g. `HOST` or `NETWORK`
+fn from_section<T: StructOpt>(section: &Properties) -> Result<T, structopt::clap::Error> {
+    let mut app = T::clap();
+    for opt in app.p.opts.iter_mut() {
+        opt.v.env = None;
+    }
+    for (_, positional) in app.p.positionals.iter_mut() {
+        positional.v.env = None;
+    }
+    Ok(T::from_clap(
+        &app.get_matches_from_safe(section_args(section))?,
+    ))
+}
+
+/// Reads the platforms declared by the `[bitcoin]`, `[liquid]` and `[clightning]` sections,
+/// in order, with their command line option names as keys.
+///
+/// Sections never fall back to the env, as the platforms share option names with the server.
+pub fn platforms_from_ini(ini: &Ini) -> Result<Vec<Platforms>, structopt::clap::Error> {
+    platforms_from_sections(ini, "")
+}
+
+/// Reads the platforms of the sections named `<prefix><platform>`
+fn platforms_from_sections(
+    ini: &Ini,
+    prefix: &str,
+) -> Result<Vec<Platforms>, structopt::clap::Error> {
+    let mut platforms = vec![];
+    for (name, section) in ini.iter() {
+        match name.and_then(|name| name.strip_prefix(prefix)) {
+            Some("bitcoin") => platforms.push(Platforms::Bitcoin(from_section(section)?)),
+            Some("liquid") => platforms.push(Platforms::Liquid(from_section(section)?)),
+            Some("clightning") => platforms.push(Platforms::CLightning(from_section(section)?)),
+            _ => {}
+        }
+    }
+    Ok(platforms)
+}
+
+/// Options of a named store, from its `[store.<name>]` section
+#[derive(Debug, Default, StructOpt, Clone, PartialEq)]
+pub struct StoreOpts {
+    /// Title of the store pages
+    #[structopt(long = "title")]
+    pub title: Option<String>,
+    /// Amount in sats of the invoices issued by the store page
+    #[structopt(long = "amount")]
+    pub amount: Option<u64>,
+    /// Fiat currency of the store prices and amount form, e.g. `EUR`
+    #[structopt(long = "currency")]
+    pub currency: Option<String>,
+    /// Price in the store currency of the invoices issued by the store page, instead of an amount
+    #[structopt(long = "price")]
+    pub price: Option<f64>,
+    /// Label of the invoices issued by the store page
+    #[structopt(long = "label")]
+    pub label: Option<String>,
+    /// Message of the invoices issued by the store page
+    #[structopt(long = "message")]
+    pub message: Option<String>,
+    /// Color of the store page header, e.g. `#f7931a`
+    #[structopt(long = "color")]
+    pub color: Option<String>,
+    /// Url of the logo shown in the store page header
+    #[structopt(long = "logo")]
+    pub logo: Option<String>,
+    /// Confirmations needed to settle the invoices of the store, instead of the server ones
+    #[structopt(long = "confirmations")]
+    pub confirmations: Option<u32>,
+}
+
+/// A named store with its own platforms
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreConfig {
+    pub name: String,
+    pub opts: StoreOpts,
+    pub platforms: Vec<Platforms>,
+}
+
+/// Reads the stores declared by the `[store.<name>]` sections, and their platforms from the
+/// `[store.<name>.bitcoin]`, `[store.<name>.liquid]` and `[store.<name>.clightning]` ones.
+///
+/// The wallets of a store default to a datadir and a wallet name of their own.
+pub fn stores_from_ini(ini: &Ini) -> Result<Vec<StoreConfig>, structopt::clap::Error> {
+    let mut stores = vec![];
+    for (name, section) in ini.iter() {
+        let name = match name.and_then(|name| name.strip_prefix("store.")) {
+            Some(name) if !name.contains('.') => name,
+            _ => continue,
+        };
+        let prefix = format!("store.{}.", name);
+        let mut platforms = platforms_from_sections(ini, &prefix)?;
+        for platform in platforms.iter_mut() {
+            let section = ini.section(Some(format!("{}{}", prefix, platform.name())));
+            let has = |key: &str| matches!(section, Some(section) if section.contains_key(key));
+            match platform {
+                Platforms::Bitcoin(opts) => {
+                    if !has("datadir") {
+                        opts.data_dir = format!("{}-{}", opts.data_dir, name);
+                    }
+                    if !has("wallet") {
+                        opts.wallet = name.to_string();
+                    }
+                }
+                Platforms::Liquid(opts) => {
+                    if !has("datadir") {
+                        opts.data_dir = format!("{}-{}", opts.data_dir, name);
+                    }
+                    if !has("wallet") {
+                        opts.wallet = name.to_string();
+                    }
+                }
+                Platforms::CLightning(opts) => {
+                    if !has("datadir") {
+                        opts.data_dir = format!("{}-{}", opts.data_dir, name);
+                    }
+                }
+            }
+        }
+        stores.push(StoreConfig {
+            name: name.to_string(),
+            opts: from_section(section)?,
+            platforms,
+        });
+    }
+    Ok(stores)
+}
+
+/// Reads the lightning node offered next to the on-chain addresses from the `[clightning]` section
+pub fn lightning_companion(ini: &Ini) -> Result<Option<ClightningOpts>, structopt::clap::Error> {
+    match ini.section(Some("clightning")) {
+        Some(section) => Ok(Some(from_section(section)?)),
+        None => Ok(None),
+    }
+}
+
 #[cfg(test)]
 mod test {
 
     use super::ConfigOpts;
-    use crate::config::load_ini_to_env;
-    use crate::config::Platforms;
+    use crate::config::{
+        lightning_companion, load_ini_to_env, platforms_from_ini, stores_from_ini,
+    };
+    use crate::config::{Platforms, ServerOpts};
     use btctipserver_bitcoin::bdk::bitcoin::Network;
     use btctipserver_bitcoin::config::{BitcoinOpts, ElectrumOpts};
+    use btctipserver_lightning::config::ClightningOpts;
     use ini::Ini;
+    use std::env;
+    use std::sync::{Mutex, PoisonError};
     use structopt;
     use structopt::StructOpt;
 
+    /// Held by the tests reading the env vars, which the ini tests set
+    static ENV: Mutex<()> = Mutex::new(());
+
     #[test]
     fn test_config_from_args() {
-        let cli_args = vec!["btctipserver", "--network", "bitcoin",
+        let _env = ENV.lock().unwrap_or_else(PoisonError::into_inner);
+        let cli_args = vec!["btctipserver", "bitcoin", "--network", "bitcoin",
                             "--descriptor", "wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)",
         ];
 
@@ -88,18 +434,43 @@ mod test {
             host: "0.0.0.0".to_string(),
             port: 8080,
             config: "config.ini".to_string(),
-            cmd: Platforms::Bitcoin( BitcoinOpts {
+            server_opts: ServerOpts {
+                expiry: 86400,
+                confirmations: 1,
+                refuse_rbf: false,
+                payment_tolerance: 0.0,
+                sync_interval: 30,
+                workers: 8,
+                max_websockets: 100,
+                request_timeout: 30,
+                rates: vec![],
+                rates_file: None,
+                rates_url: None,
+                rates_pointer: "/bitcoin/{currency}".to_string(),
+                rates_ttl: 60,
+                webhooks: vec![],
+                webhook_secret: None,
+                webhook_retries: 5,
+                public_url: None,
+                lnurl_min: 1,
+                lnurl_max: 1000000,
+                lnurl_comment: 0,
+                lightning_addresses: vec![],
+                data_dir: ".btctipserver".to_string(),
+            },
+            cmd: Some(Platforms::Bitcoin( BitcoinOpts {
                 data_dir: ".bdk-bitcoin".to_string(),
                 network: Network::Bitcoin,
                 descriptor: "wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)".parse().unwrap(),
                 wallet: "btctipserver".to_string(),
+                lightning: false,
                 electrum_opts: ElectrumOpts {
                     proxy: None,
                     retries: 5,
                     timeout: None,
                     electrum: "ssl://electrum.blockstream.info:60002".to_string()
                 }
-            })
+            }))
         };
 
         assert_eq!(expected_config_opts, config_opts);
@@ -107,8 +478,8 @@ mod test {
 
     #[test]
     fn test_config_from_ini_env() {
+        let _env = ENV.lock().unwrap_or_else(PoisonError::into_inner);
         let config = r#"
-            [BDK]
             datadir = .bdk-bitcoin
             network = bitcoin
             wallet = test
@@ -120,30 +491,181 @@ mod test {
         "#;
 
         let ini = Ini::load_from_str(config).unwrap();
-        load_ini_to_env(ini);
+        load_ini_to_env(&ini);
 
-        let cli_args = vec!["btctipserver"];
+        let cli_args = vec!["btctipserver", "bitcoin"];
 
         let config_opts: ConfigOpts = ConfigOpts::from_iter(&cli_args);
+        for (k, _) in ini.general_section().iter() {
+            env::remove_var(k.to_uppercase());
+        }
 
         let expected_config_opts = ConfigOpts {
             host: "0.0.0.0".to_string(),
             port: 8080,
             config: "config.ini".to_string(),
-            cmd: Platforms::Bitcoin( BitcoinOpts {
+            server_opts: ServerOpts {
+                expiry: 86400,
+                confirmations: 1,
+                refuse_rbf: false,
+                payment_tolerance: 0.0,
+                sync_interval: 30,
+                workers: 8,
+                max_websockets: 100,
+                request_timeout: 30,
+                rates: vec![],
+                rates_file: None,
+                rates_url: None,
+                rates_pointer: "/bitcoin/{currency}".to_string(),
+                rates_ttl: 60,
+                webhooks: vec![],
+                webhook_secret: None,
+                webhook_retries: 5,
+                public_url: None,
+                lnurl_min: 1,
+                lnurl_max: 1000000,
+                lnurl_comment: 0,
+                lightning_addresses: vec![],
+                data_dir: ".btctipserver".to_string(),
+            },
+            cmd: Some(Platforms::Bitcoin( BitcoinOpts {
                 data_dir: ".bdk-bitcoin".to_string(),
                 network: Network::Bitcoin,
                 descriptor: "wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)".parse().unwrap(),
                 wallet: "test".to_string(),
+                lightning: false,
                 electrum_opts: ElectrumOpts {
                     proxy: Some("127.0.0.1:9150".to_string()),
                     retries: 5,
                     timeout: Some(2),
                     electrum: "ssl://electrum.blockstream.info:60003".to_string()
                 }
-            }),
+            })),
         };
 
         assert_eq!(expected_config_opts, config_opts);
     }
+
+    #[test]
+    fn test_lightning_companion() {
+        let config = r#"
+            network = bitcoin
+            [clightning]
+            nodeid = 03f3c108ccd536b8526841f0a5c58212bb9e6584a1eb493080e7c1cc34f82dad71
+            host = 10.0.0.1
+            rune = rune
+            proxy = 127.0.0.1:9050
+        "#;
+        let ini = Ini::load_from_str(config).unwrap();
+        let expected = ClightningOpts {
+            data_dir: ".btctipserver-lightning".to_string(),
+            nodeid: "03f3c108ccd536b8526841f0a5c58212bb9e6584a1eb493080e7c1cc34f82dad71"
+                .to_string(),
+            host: "10.0.0.1".to_string(),
+            rune: "rune".to_string(),
+            proxy: Some("127.0.0.1:9050".to_string()),
+            timeout: 30,
+            offer: None,
+        };
+        assert_eq!(Some(expected), lightning_companion(&ini).unwrap());
+
+        let ini = Ini::load_from_str("network = bitcoin").unwrap();
+        assert_eq!(None, lightning_companion(&ini).unwrap());
+        let ini = Ini::load_from_str("[clightning]\nnodeid = 00").unwrap();
+        assert!(lightning_companion(&ini).is_err());
+    }
+
+    #[test]
+    fn test_platforms_from_ini() {
+        let config = r#"
+            port = 8080
+            [clightning]
+            nodeid = 03f3c108ccd536b8526841f0a5c58212bb9e6584a1eb493080e7c1cc34f82dad71
+            rune = rune
+            proxy = 127.0.0.1:9050
+            [bitcoin]
+            datadir = .bdk-tips
+            network = bitcoin
+            descriptor = wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)
+            wallet = tips
+            lightning = true
+        "#;
+        let ini = Ini::load_from_str(config).unwrap();
+        let platforms = platforms_from_ini(&ini).unwrap();
+        assert_eq!(2, platforms.len());
+        match &platforms[0] {
+            Platforms::CLightning(opts) => assert_eq!("rune", opts.rune),
+            _ => panic!("expected clightning first"),
+        }
+        match &platforms[1] {
+            Platforms::Bitcoin(opts) => {
+                assert_eq!(".bdk-tips", opts.data_dir);
+                assert_eq!("tips", opts.wallet);
+                assert!(opts.lightning);
+                assert_eq!(Network::Bitcoin, opts.network);
+            }
+            _ => panic!("expected bitcoin second"),
+        }
+
+        let ini = Ini::load_from_str("[bitcoin]\nnetwork = bitcoin").unwrap();
+        assert!(platforms_from_ini(&ini).is_err());
+
+        // the options missing from a section are never read from the env
+        std::env::set_var("NODEID", "00");
+        let ini = Ini::load_from_str("[clightning]\nrune = rune").unwrap();
+        assert!(platforms_from_ini(&ini).is_err());
+    }
+
+    #[test]
+    fn test_stores_from_ini() {
+        let config = r#"
+            [bitcoin]
+            descriptor = wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)
+            [store.alice]
+            title = Alice's tips
+            amount = 1000
+            confirmations = 3
+            [store.alice.bitcoin]
+            descriptor = wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)
+            [store.bob]
+            [store.bob.bitcoin]
+            descriptor = wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)
+            datadir = .bob
+            wallet = bob-tips
+        "#;
+        let ini = Ini::load_from_str(config).unwrap();
+        assert_eq!(1, platforms_from_ini(&ini).unwrap().len());
+
+        let stores = stores_from_ini(&ini).unwrap();
+        assert_eq!(2, stores.len());
+        assert_eq!("alice", stores[0].name);
+        assert_eq!(Some("Alice's tips".to_string()), stores[0].opts.title);
+        assert_eq!(Some(1000), stores[0].opts.amount);
+        assert_eq!(Some(3), stores[0].opts.confirmations);
+        match &stores[0].platforms[..] {
+            [Platforms::Bitcoin(opts)] => {
+                assert_eq!(".bdk-bitcoin-alice", opts.data_dir);
+                assert_eq!("alice", opts.wallet);
+            }
+            _ => panic!("expected a bitcoin platform"),
+        }
+        assert_eq!("bob", stores[1].name);
+        assert_eq!(None, stores[1].opts.title);
+        assert_eq!(None, stores[1].opts.confirmations);
+        match &stores[1].platforms[..] {
+            [Platforms::Bitcoin(opts)] => {
+                assert_eq!(".bob", opts.data_dir);
+                assert_eq!("bob-tips", opts.wallet);
+            }
+            _ => panic!("expected a bitcoin platform"),
+        }
+
+        // a store wallet never falls back to the descriptor of the server
+        let _env = ENV.lock().unwrap_or_else(PoisonError::into_inner);
+        env::set_var("DESCRIPTOR", "wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)");
+        let ini = Ini::load_from_str("[store.bob]\n[store.bob.bitcoin]\nwallet = bob").unwrap();
+        let stores = stores_from_ini(&ini);
+        env::remove_var("DESCRIPTOR");
+        assert!(stores.is_err());
+    }
 }
@@ -0,0 +1,106 @@
+use std::fmt;
+
+use crate::wallet::BackendError;
+
+/// Errors of the server, each one answered with an HTTP status and a message for the payer
+#[derive(Debug)]
+pub enum Error {
+    /// A payment backend failed
+    Backend(BackendError),
+    /// No invoice, store or rail matches the request
+    NotFound(String),
+    /// The request is malformed
+    BadRequest(String),
+    /// No exchange rate could be fetched
+    Rate(String),
+    /// The wallet stayed busy, e.g. syncing, longer than the request timeout
+    Busy,
+    /// The server data dir is unusable
+    Io(String),
+    /// The page could not be rendered
+    Render(String),
+}
+
+impl Error {
+    /// HTTP status code of the responses failing with this error
+    pub fn status(&self) -> u16 {
+        match self {
+            Error::Backend(BackendError::InvalidAddress(_)) | Error::BadRequest(_) => 400,
+            Error::NotFound(_) => 404,
+            Error::Backend(BackendError::Connection(_)) | Error::Rate(_) | Error::Busy => 503,
+            Error::Backend(_) | Error::Io(_) | Error::Render(_) => 500,
+        }
+    }
+
+    /// Message shown to the users, the internal details only go to the logs
+    pub fn message(&self) -> String {
+        match self {
+            Error::Backend(e @ BackendError::InvalidAddress(_)) => e.to_string(),
+            Error::Backend(BackendError::Connection(_)) => {
+                "payment backend unreachable, retry later".to_string()
+            }
+            Error::NotFound(what) => format!("{} not found", what),
+            Error::BadRequest(e) => format!("invalid request: {}", e),
+            Error::Rate(_) => "exchange rate unavailable, retry later".to_string(),
+            Error::Busy => "server busy, retry later".to_string(),
+            Error::Backend(_) | Error::Io(_) | Error::Render(_) => "internal error".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Backend(e) => write!(f, "{}", e),
+            Error::NotFound(what) => write!(f, "{} not found", what),
+            Error::BadRequest(e) => write!(f, "invalid request: {}", e),
+            Error::Rate(e) => write!(f, "exchange rate: {}", e),
+            Error::Busy => write!(f, "wallet busy"),
+            Error::Io(e) => write!(f, "io: {}", e),
+            Error::Render(e) => write!(f, "render: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Backend(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<BackendError> for Error {
+    fn from(e: BackendError) -> Self {
+        Error::Backend(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+    use crate::wallet::BackendError;
+    use std::error::Error as _;
+
+    #[test]
+    fn status() {
+        let invalid = Error::from(BackendError::InvalidAddress("bad checksum".into()));
+        assert_eq!(400, invalid.status());
+        assert_eq!("invalid address: bad checksum", invalid.message());
+
+        let unreachable = Error::from(BackendError::Connection("refused".into()));
+        assert_eq!(503, unreachable.status());
+        assert!(!unreachable.message().contains("refused"));
+
+        let database = Error::from(BackendError::Database("corrupted".into()));
+        assert_eq!(500, database.status());
+        assert_eq!("internal error", database.message());
+        let cause = database.source().and_then(|e| e.source()).unwrap();
+        assert_eq!("corrupted", cause.to_string());
+
+        assert_eq!(404, Error::NotFound("invoice".to_string()).status());
+        assert_eq!(503, Error::Busy.status());
+        assert_eq!(503, Error::Rate("timeout".to_string()).status());
+    }
+}
@@ -0,0 +1,65 @@
+use btctipserver_backend::invoice::{new_id, now, Invoice, InvoiceState};
+use btctipserver_backend::Payment;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Notification of a change in the payment state of an invoice
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaymentEvent {
+    pub id: String,
+    pub invoice_id: String,
+    pub address: String,
+    /// Received amount in sats
+    pub amount: u64,
+    pub txid: Option<String>,
+    pub payment_hash: Option<String>,
+    pub confirmations: Option<u32>,
+    pub state: InvoiceState,
+    pub timestamp: u64,
+}
+
+impl PaymentEvent {
+    /// Builds the event of `invoice`, referencing its last `payment`
+    pub fn new(invoice: &Invoice, payment: Option<&Payment>) -> Self {
+        let timestamp = now();
+        PaymentEvent {
+            id: format!("{}-{}", invoice.id, new_id()),
+            invoice_id: invoice.id.clone(),
+            address: invoice.address.clone(),
+            amount: invoice.received,
+            txid: payment.and_then(|payment| payment.txid.clone()),
+            payment_hash: payment.and_then(|payment| payment.payment_hash.clone()),
+            confirmations: payment.and_then(|payment| payment.confirmations),
+            state: invoice.state,
+            timestamp,
+        }
+    }
+}
+
+/// Broadcasts the payment events to all the subscribers
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<PaymentEvent>>>>,
+}
+
+impl EventBus {
+    /// Locks the subscribers, even if poisoned by a panicking subscriber
+    fn subscribers(&self) -> MutexGuard<'_, Vec<Sender<PaymentEvent>>> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn subscribe(&self) -> Receiver<PaymentEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers().push(sender);
+        receiver
+    }
+
+    /// Sends `event` to the subscribers, dropping the ones gone away
+    pub fn publish(&self, event: PaymentEvent) {
+        self.subscribers()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
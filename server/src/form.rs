@@ -0,0 +1,127 @@
+use url::form_urlencoded;
+
+use crate::error::Error;
+
+/// Path of the amount form, after the store one for the named stores, e.g. `/s/alice/new`
+pub const FORM_PATH: &str = "/new";
+/// Largest amount that can be requested, all the bitcoin there will ever be
+const MAX_AMOUNT: u64 = 21_000_000 * 100_000_000;
+
+/// Fields of the amount form, the empty ones are missing
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FormFields {
+    /// Requested amount in sats
+    pub amount: Option<u64>,
+    /// Requested price in the store currency
+    pub price: Option<f64>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+    /// Schema of the rail issuing the invoice
+    pub rail: Option<String>,
+}
+
+/// Reads the fields of the amount form, urlencoded in a posted body or in the query of a link
+pub fn parse(input: &str) -> Result<FormFields, Error> {
+    let mut fields = FormFields::default();
+    for (key, value) in form_urlencoded::parse(input.as_bytes()) {
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        match key.as_ref() {
+            "amount" => fields.amount = Some(parse_amount(value)?),
+            "price" => fields.price = Some(parse_price(value)?),
+            "label" => fields.label = Some(value.to_string()),
+            "message" => fields.message = Some(value.to_string()),
+            "rail" => fields.rail = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    Ok(fields)
+}
+
+fn parse_amount(value: &str) -> Result<u64, Error> {
+    match value.parse::<u64>() {
+        Ok(amount) => check_amount(amount),
+        Err(_) => Err(Error::BadRequest(format!(
+            "amount {} is not a number of sats",
+            value
+        ))),
+    }
+}
+
+fn parse_price(value: &str) -> Result<f64, Error> {
+    match value.parse::<f64>() {
+        Ok(price) => check_price(price),
+        Err(_) => Err(Error::BadRequest(format!(
+            "price {} is not a number",
+            value
+        ))),
+    }
+}
+
+/// Checks that a requested amount is at least a sat, and at most all the bitcoin
+pub fn check_amount(amount: u64) -> Result<u64, Error> {
+    match amount {
+        amount if amount > 0 && amount <= MAX_AMOUNT => Ok(amount),
+        _ => Err(Error::BadRequest(format!(
+            "amount {} is not a number of sats",
+            amount
+        ))),
+    }
+}
+
+/// Checks that a requested price is a positive number
+pub fn check_price(price: f64) -> Result<f64, Error> {
+    match price {
+        price if price.is_finite() && price > 0.0 => Ok(price),
+        _ => Err(Error::BadRequest(format!(
+            "price {} is not a number",
+            price
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::form::{check_amount, check_price, parse, FormFields};
+
+    #[test]
+    fn parse_fields() {
+        assert_eq!(FormFields::default(), parse("").unwrap());
+        assert_eq!(
+            FormFields::default(),
+            parse("amount=&label=+&message=").unwrap()
+        );
+        let fields =
+            parse("amount=1500&label=Tea+%26+cake&message=thanks%21&rail=lightning").unwrap();
+        assert_eq!(Some(1500), fields.amount);
+        assert_eq!(Some("Tea & cake".to_string()), fields.label);
+        assert_eq!(Some("thanks!".to_string()), fields.message);
+        assert_eq!(Some("lightning".to_string()), fields.rail);
+        assert_eq!(Some(1000), parse("amount=+1000+&other=1").unwrap().amount);
+        assert_eq!(Some(4.5), parse("price=4.50").unwrap().price);
+    }
+
+    #[test]
+    fn parse_invalid_amount() {
+        assert!(parse("amount=0").is_err());
+        assert!(parse("amount=-1").is_err());
+        assert!(parse("amount=0.5").is_err());
+        assert!(parse("amount=lots").is_err());
+        assert!(parse("amount=2100000000000001").is_err());
+        assert!(parse("amount=2100000000000000").is_ok());
+        assert!(parse("price=0").is_err());
+        assert!(parse("price=inf").is_err());
+        assert!(parse("price=4,50").is_err());
+    }
+
+    #[test]
+    fn check_requested_amount() {
+        assert!(check_amount(0).is_err());
+        assert!(check_amount(2_100_000_000_000_001).is_err());
+        assert_eq!(1, check_amount(1).unwrap());
+        assert!(check_price(-1.0).is_err());
+        assert!(check_price(f64::NAN).is_err());
+    }
+}
@@ -1,42 +1,88 @@
+use btctipserver_backend::invoice::FiatPrice;
 use btctipserver_bitcoin::bdk::bitcoin::Address;
-use maud::{html, Markup, DOCTYPE};
+use maud::{html, Markup, PreEscaped, DOCTYPE};
 use qr_code::bmp_monochrome::BmpError;
 use qr_code::QrCode;
 use std::io::Cursor;
 use std::str::FromStr;
 
-use crate::wallet;
-use wallet::{gen_err, Error};
+use crate::api::payment_uri;
+use crate::config::StoreOpts;
+use crate::error::Error;
+use crate::form::FormFields;
+use crate::wallet::BackendError;
 
 #[derive(Default)]
 pub struct Page {
     pub url: String,
     pub network: String,
+    /// URI scheme of the payment links of the rail, e.g. `lightning`
+    pub schema: String,
     pub address: String,
-    pub amount: Option<String>,
+    /// Bolt11 invoice offered next to the on-chain address
+    pub lightning: Option<String>,
+    /// Requested amount in sats
+    pub amount: Option<u64>,
+    /// Fiat price the amount was converted from
+    pub fiat: Option<FiatPrice>,
     pub label: Option<String>,
     pub message: Option<String>,
     pub status: Option<String>,
+    /// True if the invoice has been paid
+    pub paid: bool,
+    /// Path of the websocket pushing the status, the page reloads itself if missing
+    pub websocket: Option<String>,
+    /// Schemas of the other rails the payer can switch to
+    pub rails: Vec<String>,
+    /// Branding of the store which issued the invoice
+    pub store: StoreOpts,
+    /// Path of the form requesting an amount, offered by the pages without one
+    pub form: Option<String>,
+    /// Bech32 LNURL-pay of the store, offered by the pages without an amount
+    pub lnurl: Option<String>,
+    /// Lightning Address of the store, offered next to its LNURL
+    pub lightning_address: Option<String>,
+}
+
+/// Amount form of a store, prefilled with `fields`
+#[derive(Default)]
+pub struct Form {
+    /// Path the form is posted to
+    pub action: String,
+    pub fields: FormFields,
+    /// Schemas of the rails the payer can pick, the first one is the default
+    pub rails: Vec<String>,
+    /// Fiat currency of the price asked instead of the amount in sats
+    pub currency: Option<String>,
+    pub store: StoreOpts,
 }
 
 const CSS2: &str = include_str!("../../assets/css/style.css");
 const CSS1: &str = include_str!("../../assets/css/styles.css");
+const STATUS_JS: &str = include_str!("../../assets/js/status.js");
 
-fn inner_header(title: &str) -> Markup {
-    let header = html! {
+fn inner_header(title: &str, store: &StoreOpts) -> Markup {
+    let title = store.title.as_deref().unwrap_or(title);
+    let style = match &store.color {
+        Some(color) => format!("background: {}", color),
+        None => String::new(),
+    };
+    html! {
             header.header {
                 div.header__inner {
                     div.header__logo {
                         a href="//" {
-                            div.logo {
+                            div.logo style=(style) {
+                              @if let Some(logo) = &store.logo {
+                                  img src=(logo) alt="" height="32";
+                              }
                               (title)
                             }
                         }
                     }
                 }
             }
-    };
-    return header;
+    }
 }
 
 fn inner_section(text: &str) -> Markup {
@@ -93,24 +139,87 @@ pub fn not_found() -> String {
     html.into_string()
 }
 
-fn address_link(network: &str, address: &str) -> Result<String, Error> {
-    Ok(format!("{}:{}", network, address))
+/// Page of a failed request, showing `message` to the payer
+pub fn error(status: u16, message: &str) -> String {
+    let html = html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="UTF-8";
+                meta name="robots" content="noindex";
+                title { (status) }
+                style { (CSS1) }
+                style { (CSS2) }
+            }
+            body {
+                div.container.center.headings--one-size {
+                    h1 { (status) }
+                    p { (message) }
+                }
+            }
+        }
+    };
+    html.into_string()
+}
+
+fn address_link(page: &Page) -> String {
+    match (page.schema.as_str(), &page.lightning) {
+        ("bitcoin", _) => payment_uri(
+            "bitcoin",
+            &page.address,
+            page.amount,
+            &page.label,
+            &page.message,
+            &page.lightning,
+        ),
+        (schema, Some(lightning)) => {
+            format!("{}:{}?lightning={}", schema, page.address, lightning)
+        }
+        (schema, None) => format!("{}:{}", schema, page.address),
+    }
 }
 
-fn address_qr(network: &str, address: &str) -> Result<String, Error> {
-    match network {
-        "bitcoin" | "testnet" => Ok(Address::from_str(address)
-            .map_err(|_| gen_err())?
-            .to_qr_uri()),
-        _ => Ok(address_link(network, address)?),
+fn address_qr(page: &Page) -> Result<String, Error> {
+    match page.schema.as_str() {
+        // uppercase bech32 strings fit in smaller QR codes
+        "bitcoin" => {
+            let uri = Address::from_str(&page.address)
+                .map_err(|e| BackendError::InvalidAddress(Box::new(e)))?
+                .to_qr_uri();
+            // the BIP21 parameters are kept as they are, but the invoice is uppercased too
+            let link = payment_uri(
+                "bitcoin",
+                &page.address,
+                page.amount,
+                &page.label,
+                &page.message,
+                &None,
+            );
+            let mut params: Vec<String> = link.splitn(2, '?').skip(1).map(String::from).collect();
+            if let Some(lightning) = &page.lightning {
+                params.push(format!("lightning={}", lightning.to_uppercase()));
+            }
+            match params.is_empty() {
+                true => Ok(uri),
+                false => Ok(format!("{}?{}", uri, params.join("&"))),
+            }
+        }
+        "lightning" => Ok(format!("LIGHTNING:{}", page.address.to_uppercase())),
+        _ => Ok(address_link(page)),
     }
 }
 
 pub fn render(page: Page) -> Result<String, Error> {
-    let meta_http_content = format!("{}; URL=/?{}", 10, page.url);
-    let address_link = address_link(page.network.as_str(), page.address.as_str())?;
-    let address_qr = address_qr(page.network.as_str(), page.address.as_str())?;
-    let qr = create_bmp_base64_qr(&address_qr).map_err(|_| gen_err())?;
+    let meta_http_content = format!("{}; URL={}", 10, page.url);
+    let address_link = address_link(&page);
+    let address_qr = address_qr(&page)?;
+    let qr = create_bmp_base64_qr(&address_qr).map_err(|e| Error::Render(format!("{:?}", e)))?;
+    let lnurl_qr = match &page.lnurl {
+        Some(lnurl) => {
+            Some(create_bmp_base64_qr(lnurl).map_err(|e| Error::Render(format!("{:?}", e)))?)
+        }
+        None => None,
+    };
 
     let html = html! {
         (DOCTYPE)
@@ -118,26 +227,41 @@ pub fn render(page: Page) -> Result<String, Error> {
             head {
                 meta charset="UTF-8";
                 meta name="robots" content="noindex";
-                meta http-equiv="Refresh" content=(meta_http_content);
+                @if page.websocket.is_none() {
+                    meta http-equiv="Refresh" content=(meta_http_content);
+                }
                 title { (page.address) }
                 style { (CSS1) }
                 style { (CSS2) }
             }
             body {
                 div.container.center.headings--one-size {
-                    (inner_header(page.network.as_str()))
+                    (inner_header(page.network.as_str(), &page.store))
                     div.content {
                         div.index-content {
 
+                            div.framed.framed-paragraph id="paid" style=(if page.paid { "" } else { "display: none" }) {
+                                h2 class="center" { "Paid" }
+                            }
                             div.framed.framed-paragraph {
                                 div class="center" {
                                     img class="qr" src=(qr) { }
                                     br { }
                                     (inner_section(page.address.as_str()))
+                                    @if let Some(lightning) = &page.lightning {
+                                        (inner_section(lightning.as_str()))
+                                    }
                                 }
                             }
-                            @if let Some(amount) = &page.amount {
-                                (inner_section(format!("Amount {} sats", amount.to_string().as_str()).as_str()))
+                            @match (&page.fiat, &page.amount) {
+                                (Some(fiat), Some(amount)) => {
+                                    (inner_section(format!("Amount {:.2} {} ({} sats)", fiat.amount, fiat.currency, amount).as_str()))
+                                    (inner_section(format!("Rate 1 BTC = {:.2} {}", fiat.rate, fiat.currency).as_str()))
+                                }
+                                (_, Some(amount)) => {
+                                    (inner_section(format!("Amount {} sats", amount).as_str()))
+                                }
+                                (_, None) => {}
                             }
                             @if let Some(label) = &page.label {
                                 (inner_section(format!("Label {}", label.to_string().as_str()).as_str()))
@@ -146,18 +270,110 @@ pub fn render(page: Page) -> Result<String, Error> {
                                 (inner_section(format!("Message {}", message.to_string().as_str()).as_str()))
                             }
                             @if let Some(status) = &page.status {
-                                (inner_section(format!("{}", status.to_string().as_str()).as_str()))
+                                div id="status" {
+                                    (inner_section(status))
+                                }
                             }
                             a href=(address_link) { "Open in wallet app" }
+                            @for rail in &page.rails {
+                                br { }
+                                a href=(format!("{}?rail={}", page.url, rail)) { "Pay with " (rail) }
+                            }
+                            @if let Some(form) = &page.form {
+                                br { }
+                                a href=(form) { "Request an amount" }
+                            }
+                            @if let (Some(lnurl), Some(lnurl_qr)) = (&page.lnurl, &lnurl_qr) {
+                                div.framed.framed-paragraph {
+                                    div class="center" {
+                                        img class="qr" src=(lnurl_qr) { }
+                                        br { }
+                                        (inner_section(lnurl.as_str()))
+                                        @if let Some(address) = &page.lightning_address {
+                                            (inner_section(format!("Lightning Address {}", address).as_str()))
+                                        }
+                                        a href=(format!("lightning:{}", lnurl)) { "Pay any amount with LNURL" }
+                                    }
+                                }
+                            }
                         }
                     }
                 }
+                @if let Some(websocket) = &page.websocket {
+                    script {
+                        (PreEscaped(format!("var STATUS_URL = {};", serde_json::to_string(websocket).unwrap())))
+                        (PreEscaped(STATUS_JS))
+                    }
+                }
             }
         }
     };
     Ok(html.into_string())
 }
 
+/// Page of the amount form, issuing a fixed-amount invoice once posted
+pub fn render_form(form: Form) -> String {
+    let fields = &form.fields;
+    let html = html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="UTF-8";
+                meta name="robots" content="noindex";
+                title { "Request an amount" }
+                style { (CSS1) }
+                style { (CSS2) }
+            }
+            body {
+                div.container.center.headings--one-size {
+                    (inner_header("Request an amount", &form.store))
+                    div.content {
+                        div.index-content {
+                            form method="post" action=(form.action) {
+                                @match &form.currency {
+                                    Some(currency) => p {
+                                        label for="price" { "Amount (" (currency) ")" }
+                                        br { }
+                                        input type="number" id="price" name="price" min="0.01" step="0.01" required value=(fields.price.map(|p| p.to_string()).unwrap_or_default());
+                                    },
+                                    None => p {
+                                        label for="amount" { "Amount (sats)" }
+                                        br { }
+                                        input type="number" id="amount" name="amount" min="1" step="1" required value=(fields.amount.map(|a| a.to_string()).unwrap_or_default());
+                                    },
+                                }
+                                p {
+                                    label for="label" { "Label" }
+                                    br { }
+                                    input type="text" id="label" name="label" value=(fields.label.as_deref().unwrap_or(""));
+                                }
+                                p {
+                                    label for="message" { "Message" }
+                                    br { }
+                                    input type="text" id="message" name="message" value=(fields.message.as_deref().unwrap_or(""));
+                                }
+                                @if form.rails.len() > 1 {
+                                    p {
+                                        label for="rail" { "Pay with" }
+                                        br { }
+                                        select id="rail" name="rail" {
+                                            @for rail in &form.rails {
+                                                option value=(rail) selected[fields.rail.as_ref() == Some(rail)] { (rail) }
+                                            }
+                                        }
+                                    }
+                                }
+                                button type="submit" { "Create invoice" }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    };
+    html.into_string()
+}
+
 pub fn redirect(link: &str) -> Result<String, std::io::Error> {
     let meta_http_content = format!("{}; URL={}", 0, link);
     let html = html! {
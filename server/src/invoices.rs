@@ -0,0 +1,269 @@
+use btctipserver_backend::invoice::{new_id, now, FiatPrice, Invoice, InvoiceState};
+use btctipserver_backend::InvoiceParams;
+use btctipserver_lightning::amount::MilliSatoshi;
+use log::warn;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::ServerOpts;
+use crate::error::Error;
+use crate::events::{EventBus, PaymentEvent};
+use crate::rail::{self, Rail};
+use crate::wallet::Wallet;
+
+/// Details requested by the payer for a new invoice
+#[derive(Debug, Default, Clone)]
+pub struct InvoiceRequest {
+    /// Requested amount in sats
+    pub amount: Option<u64>,
+    /// Fiat price the amount was converted from
+    pub fiat: Option<FiatPrice>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+    /// Confirmations needed to settle the invoice, the server default if missing
+    pub confirmations: Option<u32>,
+    /// Exact amount of the lightning invoice in msat, which may hold a fraction of `amount`
+    pub amount_msat: Option<u64>,
+    /// Description of the lightning invoice, which only commits to its hash, in place of the
+    /// label and message, e.g. the LNURL-pay metadata
+    pub hashed_description: Option<String>,
+}
+
+impl InvoiceRequest {
+    /// Requests the amount of the fiat `price`, at its locked rate
+    pub fn priced(self, price: FiatPrice) -> Self {
+        InvoiceRequest {
+            amount: Some(price.sats()),
+            fiat: Some(price),
+            ..self
+        }
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.amount.is_none()
+            && self.label.is_none()
+            && self.message.is_none()
+            && self.confirmations.is_none()
+    }
+}
+
+/// Unpaid invoices an on-chain rail keeps open with addresses of their own, well below the stop
+/// gap of the wallet sync (20), so that the payments to the later addresses are still found
+const MAX_UNPAID_ADDRESSES: usize = 10;
+
+/// Creates and persists a new invoice, following the expiry and confirmation policy of `opts`.
+///
+/// Anonymous requests share the pending invoice of the last unused address, while requests with
+/// an amount, label or message get an address on their own. On-chain rails share the unpaid
+/// invoice of an identical request instead, and refuse new addresses past
+/// [`MAX_UNPAID_ADDRESSES`] unpaid ones.
+pub fn create(rail: &Rail, req: InvoiceRequest, opts: &ServerOpts) -> Result<Invoice, Error> {
+    let timeout = Duration::from_secs(opts.request_timeout);
+    let id = new_id();
+    let params = params(&id, &req, opts)?;
+    let address = match req.is_anonymous() {
+        true => {
+            let address = rail::lock(&rail.wallet, timeout)?.last_unused_address(&params)?;
+            match rail.invoices.find_by_address(&address)? {
+                Some(invoice)
+                    if invoice.is_anonymous()
+                        && invoice.state == InvoiceState::New
+                        && !invoice.is_expired(now()) =>
+                {
+                    return Ok(invoice)
+                }
+                Some(_) => rail::lock(&rail.wallet, timeout)?.new_address(&params)?,
+                None => address,
+            }
+        }
+        false if rail.is_onchain() => {
+            let unpaid: Vec<Invoice> = rail
+                .invoices
+                .pending()?
+                .into_iter()
+                .filter(|invoice| invoice.state == InvoiceState::New && !invoice.is_expired(now()))
+                .collect();
+            if let Some(invoice) = unpaid.iter().rev().find(|i| same_request(i, &req, opts)) {
+                return Ok(invoice.clone());
+            }
+            let mut addresses: Vec<&str> = unpaid.iter().map(|i| i.address.as_str()).collect();
+            addresses.sort_unstable();
+            addresses.dedup();
+            if addresses.len() >= MAX_UNPAID_ADDRESSES {
+                warn!(
+                    "{} unpaid {} invoices, no new address",
+                    addresses.len(),
+                    rail.schema
+                );
+                return Err(Error::Busy);
+            }
+            rail::lock(&rail.wallet, timeout)?.invoice_address(&params)?
+        }
+        false => rail::lock(&rail.wallet, timeout)?.invoice_address(&params)?,
+    };
+    persist(rail, id, address, req, opts)
+}
+
+/// Returns true if `invoice` was issued for a request identical to `req`
+fn same_request(invoice: &Invoice, req: &InvoiceRequest, opts: &ServerOpts) -> bool {
+    invoice.amount == req.amount
+        && invoice.fiat == req.fiat
+        && invoice.label == req.label
+        && invoice.message == req.message
+        && invoice.required_confirmations == req.confirmations.unwrap_or(opts.confirmations)
+}
+
+/// Issues a bolt11 invoice of the lightning `wallet` alone, neither persisted nor offered next
+/// to an on-chain address, e.g. for the LNURL-pay wallets of a rail with a lightning companion
+pub fn bolt11(
+    wallet: &Mutex<Wallet>,
+    req: &InvoiceRequest,
+    opts: &ServerOpts,
+) -> Result<String, Error> {
+    let timeout = Duration::from_secs(opts.request_timeout);
+    let params = params(&new_id(), req, opts)?;
+    Ok(rail::lock(wallet, timeout)?.invoice_address(&params)?)
+}
+
+/// Persists a new invoice for `address`, which must be issued by the backend.
+pub fn register(
+    rail: &Rail,
+    address: String,
+    req: InvoiceRequest,
+    opts: &ServerOpts,
+) -> Result<Invoice, Error> {
+    persist(rail, new_id(), address, req, opts)
+}
+
+/// Details embedded in the lightning invoices, which are labelled with the invoice `id` and
+/// expire with it
+fn params(id: &str, req: &InvoiceRequest, opts: &ServerOpts) -> Result<InvoiceParams, Error> {
+    let amount_msat = match (req.amount_msat, req.amount) {
+        (Some(msat), _) => Some(msat),
+        (None, Some(amount)) => Some(
+            MilliSatoshi::from_sat(amount)
+                .ok_or_else(|| Error::BadRequest(format!("amount {} too large", amount)))?
+                .as_msat(),
+        ),
+        (None, None) => None,
+    };
+    let description = match (&req.hashed_description, &req.label, &req.message) {
+        (Some(description), _, _) => Some(description.clone()),
+        (None, Some(label), Some(message)) => Some(format!("{}: {}", label, message)),
+        (None, Some(text), None) | (None, None, Some(text)) => Some(text.clone()),
+        (None, None, None) => None,
+    };
+    Ok(InvoiceParams {
+        id: id.to_string(),
+        amount_msat,
+        description,
+        description_hash_only: req.hashed_description.is_some(),
+        expiry: Some(opts.expiry),
+        ..Default::default()
+    })
+}
+
+fn persist(
+    rail: &Rail,
+    id: String,
+    address: String,
+    req: InvoiceRequest,
+    opts: &ServerOpts,
+) -> Result<Invoice, Error> {
+    let lightning = match &rail.companion {
+        Some(companion) => {
+            let timeout = Duration::from_secs(opts.request_timeout);
+            // the on-chain address is the fallback of the lightning invoice
+            let params = InvoiceParams {
+                fallback: Some(address.clone()),
+                ..params(&id, &req, opts)?
+            };
+            // the on-chain invoice is issued even when the node is unavailable
+            let bolt11 = match rail::lock(companion, timeout) {
+                Ok(mut wallet) => wallet.invoice_address(&params).map_err(Error::from),
+                Err(e) => Err(e),
+            };
+            match bolt11 {
+                Ok(bolt11) => Some(bolt11),
+                Err(e) => {
+                    warn!("invoice {} issued without a lightning invoice: {}", id, e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let mut invoice = Invoice::new(
+        &rail.schema,
+        address,
+        &rail.currency,
+        req.amount,
+        req.label,
+        req.message,
+        Some(opts.expiry),
+    );
+    invoice.id = id;
+    invoice.fiat = req.fiat;
+    invoice.lightning = lightning;
+    invoice.required_confirmations = req.confirmations.unwrap_or(opts.confirmations);
+    invoice.refuse_rbf = opts.refuse_rbf;
+    invoice.tolerance = invoice
+        .amount
+        .map(|amount| (amount as f64 * opts.payment_tolerance / 100.0) as u64)
+        .unwrap_or(0);
+    rail.invoices.insert(&invoice)?;
+    rail.watch(&invoice.address);
+    Ok(invoice)
+}
+
+/// Returns the status line of the payment page
+pub fn summary(invoice: &Invoice) -> String {
+    format!(
+        "Status {}, received {} sats",
+        invoice.status(),
+        invoice.received
+    )
+}
+
+/// Looks up an invoice by id, or by address for the links issued before invoices existed
+pub fn find(rail: &Rail, id: &str) -> Result<Option<Invoice>, Error> {
+    match rail.invoices.get(id)? {
+        Some(invoice) => Ok(Some(invoice)),
+        None => Ok(rail.invoices.find_by_address(id)?),
+    }
+}
+
+/// Updates the state of `invoice` with the payments received so far on any of its rails,
+/// persisting and publishing any change.
+pub fn refresh(rail: &Rail, events: &EventBus, invoice: &mut Invoice) -> Result<bool, Error> {
+    if !invoice.state.is_pending() {
+        return Ok(false);
+    }
+    let payments = rail::lock_wait(&rail.wallet).payments(&invoice.address)?;
+    let mut payments = rail.invoices.attribute(invoice, payments)?;
+    let mut unknown = false;
+    if let (Some(bolt11), Some(companion)) = (&invoice.lightning, &rail.companion) {
+        match rail::lock_wait(companion).payments(bolt11) {
+            Ok(lightning) => payments.extend(lightning),
+            Err(e) => {
+                warn!(
+                    "lightning payments of invoice {} unknown: {}",
+                    invoice.id, e
+                );
+                unknown = true;
+            }
+        }
+    }
+    let mut updated = invoice.clone();
+    let changed = updated.update(&payments, now());
+    // without its lightning payments, an invoice is never given up on
+    if unknown && !updated.state.is_pending() && !updated.state.is_paid() {
+        return Ok(false);
+    }
+    *invoice = updated;
+    if changed {
+        rail.invoices.insert(invoice)?;
+        events.publish(PaymentEvent::new(invoice, payments.last()));
+    }
+    Ok(changed)
+}
@@ -0,0 +1,17 @@
+pub mod api;
+pub mod config;
+pub mod error;
+pub mod events;
+pub mod form;
+pub mod html;
+pub mod invoices;
+pub mod lnurl;
+pub mod monitor;
+pub mod rail;
+pub mod rates;
+pub mod server;
+pub mod shutdown;
+pub mod store;
+pub mod wallet;
+pub mod webhook;
+pub mod websocket;
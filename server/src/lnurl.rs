@@ -0,0 +1,316 @@
+use btctipserver_bitcoin::bdk::bitcoin::bech32::{self, ToBase32, Variant};
+use btctipserver_lightning::amount::MilliSatoshi;
+use log::warn;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::config::ServerOpts;
+use crate::error::Error;
+use crate::invoices::{self, InvoiceRequest};
+use crate::rail::Rail;
+use crate::rates::Rates;
+use crate::store::{Store, Stores};
+
+/// Path of the LNURL-pay endpoints, followed by the store name or the Lightning Address username
+pub const LNURLP_PATH: &str = "/.well-known/lnurlp/";
+/// Suffix of the LNURL-pay callback path, issuing the invoices
+const CALLBACK_SUFFIX: &str = "/callback";
+/// Name of the default store in the LNURL-pay paths
+pub const DEFAULT_NAME: &str = "tips";
+
+/// First answer of LNURL-pay, describing the payments the store accepts
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PayRequest {
+    pub tag: &'static str,
+    pub callback: String,
+    /// JSON array of `[type, content]` entries, its hash is the description hash of the invoices
+    pub metadata: String,
+    /// Amounts in msat
+    pub min_sendable: u64,
+    pub max_sendable: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment_allowed: Option<u32>,
+}
+
+/// Answer of the callback, the invoice of the amount chosen by the payer
+#[derive(Debug, Serialize)]
+pub struct PayResponse {
+    pub pr: String,
+    pub routes: Vec<String>,
+}
+
+/// Name of `store` in the LNURL-pay paths
+pub fn name(store: &Store) -> &str {
+    match store.name.is_empty() {
+        true => DEFAULT_NAME,
+        false => &store.name,
+    }
+}
+
+/// Returns the `(username, store)` entries of the Lightning Addresses
+fn usernames(opts: &ServerOpts) -> impl Iterator<Item = (String, &str)> {
+    opts.lightning_addresses.iter().map(|entry| {
+        let (username, store) = entry.split_once('=').unwrap_or((entry, ""));
+        (username.trim().to_lowercase(), store.trim())
+    })
+}
+
+/// Domain of the Lightning Addresses, the host of the public url
+fn domain(opts: &ServerOpts) -> Option<String> {
+    let public_url = url::Url::parse(opts.public_url.as_ref()?).ok()?;
+    public_url.host_str().map(|host| host.to_string())
+}
+
+/// Returns the store served as `name`, with its Lightning Address if `name` is a username
+pub fn find<'a>(
+    stores: &'a Stores,
+    opts: &ServerOpts,
+    name: &str,
+) -> Option<(&'a Store, Option<String>)> {
+    let username = name.to_lowercase();
+    if let Some((_, store)) = usernames(opts).find(|(u, _)| *u == username) {
+        let address = domain(opts).map(|domain| format!("{}@{}", username, domain));
+        return Some((stores.get(store)?, address));
+    }
+    let store = stores.iter().find(|store| self::name(store) == name)?;
+    Some((store, None))
+}
+
+/// Returns the Lightning Address of `store`, the one of its first username
+pub fn address(store: &Store, opts: &ServerOpts) -> Option<String> {
+    url(store, opts)?;
+    let (username, _) = usernames(opts).find(|(_, s)| *s == store.name)?;
+    Some(format!("{}@{}", username, domain(opts)?))
+}
+
+/// Checks that every Lightning Address has a valid username and is served by a store with a
+/// lightning rail
+pub fn check_addresses(stores: &Stores, opts: &ServerOpts) -> Result<(), String> {
+    if opts.lightning_addresses.is_empty() {
+        return Ok(());
+    }
+    if domain(opts).is_none() {
+        return Err("the Lightning Addresses need a public url".to_string());
+    }
+    let mut seen = vec![];
+    for (username, name) in usernames(opts) {
+        let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c);
+        if username.is_empty() || !username.chars().all(valid) {
+            return Err(format!("invalid username {}", username));
+        }
+        if seen.contains(&username) {
+            return Err(format!("duplicate username {}", username));
+        }
+        let store = stores
+            .get(name)
+            .ok_or_else(|| format!("unknown store {} of {}", name, username))?;
+        if rail(store).is_none() {
+            return Err(format!("the store of {} has no lightning rail", username));
+        }
+        seen.push(username);
+    }
+    Ok(())
+}
+
+/// Returns the rail issuing the lightning invoices of `store`, its lightning one or else the
+/// one with a lightning companion
+pub fn rail(store: &Store) -> Option<&Rail> {
+    store
+        .rails
+        .get("lightning")
+        .or_else(|| store.rails.iter().find(|rail| rail.companion.is_some()))
+}
+
+/// Returns the LNURL-pay url of `name`, if the server has a public url
+fn endpoint(opts: &ServerOpts, name: &str) -> Option<String> {
+    let public_url = opts.public_url.as_ref()?;
+    Some(format!(
+        "{}{}{}",
+        public_url.trim_end_matches('/'),
+        LNURLP_PATH,
+        name
+    ))
+}
+
+/// Returns the LNURL-pay url of `store`, if the server has a public url and the store a
+/// lightning rail
+pub fn url(store: &Store, opts: &ServerOpts) -> Option<String> {
+    rail(store)?;
+    endpoint(opts, name(store))
+}
+
+/// Encodes `url` as a bech32 LNURL, uppercase to fit a smaller QR code
+pub fn encode(url: &str) -> String {
+    bech32::encode("lnurl", url.as_bytes().to_base32(), Variant::Bech32)
+        .unwrap()
+        .to_uppercase()
+}
+
+/// Describes the payments to `store`, through its Lightning `address` if any, to the payer
+/// wallet
+fn metadata(store: &Store, address: Option<&str>) -> String {
+    let text = match (&store.opts.message, &store.opts.label) {
+        (Some(text), _) | (None, Some(text)) => text.clone(),
+        (None, None) => match address {
+            Some(address) => format!("Tip {}", address),
+            None => format!("Tip {}", name(store)),
+        },
+    };
+    match address {
+        Some(address) => json!([["text/plain", text], ["text/identifier", address]]),
+        None => json!([["text/plain", text]]),
+    }
+    .to_string()
+}
+
+/// Returns the amount bounds in msat, a payment is at least a sat
+fn sendable(opts: &ServerOpts) -> (u64, u64) {
+    let msat = |sats: u64| MilliSatoshi::from_sat(sats).map_or(u64::MAX, |m| m.as_msat());
+    (msat(opts.lnurl_min.max(1)), msat(opts.lnurl_max))
+}
+
+/// Describes the payments to `store`, requested as `name`
+pub fn pay_request(
+    store: &Store,
+    address: Option<&str>,
+    opts: &ServerOpts,
+    name: &str,
+) -> Result<PayRequest, Error> {
+    let url = rail(store)
+        .and_then(|_| endpoint(opts, name))
+        .ok_or_else(|| Error::NotFound("lnurl".to_string()))?;
+    let (min_sendable, max_sendable) = sendable(opts);
+    Ok(PayRequest {
+        tag: "payRequest",
+        callback: format!("{}{}", url, CALLBACK_SUFFIX),
+        metadata: metadata(store, address),
+        min_sendable,
+        max_sendable,
+        comment_allowed: Some(opts.lnurl_comment).filter(|len| *len > 0),
+    })
+}
+
+/// Issues the invoice of the `amount` in msat of the callback `query`, committing to the
+/// metadata hash
+pub fn callback(
+    store: &Store,
+    address: Option<&str>,
+    rates: &Rates,
+    opts: &ServerOpts,
+    query: &str,
+) -> Result<PayResponse, Error> {
+    let rail = rail(store).ok_or_else(|| Error::NotFound("lnurl".to_string()))?;
+    let params: HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    let amount = params
+        .get("amount")
+        .ok_or_else(|| Error::BadRequest("missing amount".to_string()))?;
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| Error::BadRequest(format!("amount {} is not a number of msat", amount)))?;
+    let (min_sendable, max_sendable) = sendable(opts);
+    if amount < min_sendable || amount > max_sendable {
+        return Err(Error::BadRequest(format!(
+            "amount must be between {} and {} msat",
+            min_sendable, max_sendable
+        )));
+    }
+    let comment = params.get("comment").filter(|c| !c.is_empty()).cloned();
+    if let Some(comment) = &comment {
+        if comment.chars().count() > opts.lnurl_comment as usize {
+            return Err(Error::BadRequest("comment too long".to_string()));
+        }
+    }
+    let req = InvoiceRequest {
+        amount: Some(MilliSatoshi::from_msat(amount).to_sat()),
+        amount_msat: Some(amount),
+        hashed_description: Some(metadata(store, address)),
+        message: comment,
+        ..Default::default()
+    };
+    let req = store.complete(req, rates)?;
+    let pr = match &rail.companion {
+        // the companion issues the invoice alone, without an on-chain address to fall back to
+        Some(companion) if rail.schema != "lightning" => invoices::bolt11(companion, &req, opts)?,
+        _ => invoices::create(rail, req, opts)?.address,
+    };
+    Ok(PayResponse { pr, routes: vec![] })
+}
+
+/// Answers the LNURL-pay request to `path`, returning the status code and the JSON body
+pub fn handle(
+    stores: &Stores,
+    rates: &Rates,
+    opts: &ServerOpts,
+    path: &str,
+    query: &str,
+) -> (u16, String) {
+    let path = &path[LNURLP_PATH.len()..];
+    let (name, is_callback) = match path.strip_suffix(CALLBACK_SUFFIX) {
+        Some(name) => (name, true),
+        None => (path, false),
+    };
+    let result = find(stores, opts, name)
+        .ok_or_else(|| Error::NotFound("lnurl".to_string()))
+        .and_then(|(store, address)| {
+            let address = address.as_deref();
+            match is_callback {
+                true => callback(store, address, rates, opts, query).map(|res| json!(res)),
+                false => pay_request(store, address, opts, name).map(|res| json!(res)),
+            }
+        });
+    match result {
+        Ok(json) => (200, json.to_string()),
+        Err(e) => {
+            if e.status() >= 500 {
+                warn!("lnurl request failed: {}", e);
+            }
+            let json = json!({ "status": "ERROR", "reason": e.message() });
+            (e.status(), json.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::ServerOpts;
+    use crate::lnurl::{domain, encode, sendable, usernames};
+    use structopt::StructOpt;
+
+    #[test]
+    fn sendable_bounds() {
+        let opts = ServerOpts::from_iter(&["test", "--lnurl-min", "0", "--lnurl-max", "5000"]);
+        assert_eq!((1000, 5_000_000), sendable(&opts));
+        let opts = ServerOpts::from_iter(&["test", "--lnurl-max", &u64::MAX.to_string()]);
+        assert_eq!((1000, u64::MAX), sendable(&opts));
+    }
+
+    #[test]
+    fn lightning_addresses() {
+        let opts = ServerOpts::from_iter(&[
+            "test",
+            "--public-url",
+            "https://tips.example.com:8443/",
+            "--lightning-address",
+            "Tips,alice = alice",
+        ]);
+        assert_eq!(
+            vec![("tips".to_string(), ""), ("alice".to_string(), "alice")],
+            usernames(&opts).collect::<Vec<_>>()
+        );
+        assert_eq!(Some("tips.example.com".to_string()), domain(&opts));
+        assert_eq!(None, domain(&ServerOpts::from_iter(&["test"])));
+    }
+
+    #[test]
+    fn encode_lnurl() {
+        // from LUD-01
+        assert_eq!(
+            "LNURL1DP68GURN8GHJ7UM9WFMXJCM99E3K7MF0V9CXJ0M385EKVCENXC6R2C35XVUKXEFCV5MKVV34X5EKZD3EV56NYD3HXQURZEPEXEJXXEPNXSCRVWFNV9NXZCN9XQ6XYEFHVGCXXCMYXYMNSERXFQ5FNS",
+            encode("https://service.com/api?q=3fc3645b439ce8e7f2553a69e5267081d96dcd340693afabe04be7b0ccd178df")
+        );
+    }
+}
@@ -1,17 +1,20 @@
-mod config;
-mod html;
-mod server;
-mod wallet;
-
 use btctipserver_bitcoin::BTCWallet;
+use btctipserver_lightning::config::ClightningOpts;
 use btctipserver_lightning::ClightningWallet;
 use btctipserver_liquid::LiquidWallet;
 
-use crate::config::{ConfigOpts, Platforms};
-use crate::wallet::Wallet;
+use btctipserver::config::{self, ConfigOpts, Platforms, StoreOpts};
+use btctipserver::rail::{Rail, Rails};
+use btctipserver::rates::Rates;
+use btctipserver::store::{Store, Stores};
+use btctipserver::wallet::Wallet;
+use btctipserver::{lnurl, server};
 
 use ini::Ini;
 use std::env;
+use std::fmt;
+use std::process;
+use std::sync::{Arc, Mutex};
 use structopt::StructOpt;
 
 fn main() {
@@ -23,21 +26,155 @@ fn main() {
         Some(i) => &args[i + 1],
         None => "config.ini",
     };
-    let _ = Ini::load_from_file(config_file).map(config::load_ini_to_env);
+    let ini = Ini::load_from_file(config_file).ok();
+    if let Some(ini) = &ini {
+        config::load_ini_to_env(ini);
+    }
 
     // Read env and commandline args
     let conf: ConfigOpts = ConfigOpts::from_args();
-    let wallet = match conf.cmd {
-        Platforms::Bitcoin(opts) => Wallet::BTCWallet(BTCWallet::new(&opts).unwrap()),
-        Platforms::Liquid(opts) => Wallet::LiquidWallet(LiquidWallet::new(&opts).unwrap()),
-        Platforms::CLightning(opts) => {
-            Wallet::ClightningWallet(ClightningWallet::new(&opts).unwrap())
-        }
+
+    // Serve the platform of the subcommand, or else all the ones of the config file sections,
+    // next to the named stores of the config file
+    let platforms = match (conf.cmd.clone(), &ini) {
+        (Some(platform), _) => vec![platform],
+        (None, Some(ini)) => config::platforms_from_ini(ini).unwrap_or_else(|e| e.exit()),
+        (None, None) => vec![],
     };
+    let named = match &ini {
+        Some(ini) => config::stores_from_ini(ini).unwrap_or_else(|e| e.exit()),
+        None => vec![],
+    };
+    let mut companion = Companion {
+        opts: match &ini {
+            Some(ini) => config::lightning_companion(ini).unwrap_or_else(|e| e.exit()),
+            None => None,
+        },
+        wallet: None,
+    };
+    let mut stores = vec![];
+    if !platforms.is_empty() {
+        let rails = open_rails(&platforms, &mut companion);
+        stores.push(Store::new("", StoreOpts::default(), rails));
+    }
+    for store in named {
+        if store.platforms.is_empty() {
+            eprintln!("Store {} has no platform to serve", store.name);
+            process::exit(1);
+        }
+        if store.opts.price.is_some() && store.opts.currency.is_none() {
+            eprintln!("Store {} has a price but no currency", store.name);
+            process::exit(1);
+        }
+        let rails = open_rails(&store.platforms, &mut companion);
+        stores.push(Store::new(&store.name, store.opts, rails));
+    }
+    if stores.is_empty() {
+        eprintln!(
+            "No platform to serve, pass one as subcommand or declare them in the config file"
+        );
+        process::exit(1);
+    }
+
+    if conf.server_opts.lnurl_min > conf.server_opts.lnurl_max {
+        eprintln!("The LNURL minimum amount is above the maximum");
+        process::exit(1);
+    }
+
+    let stores = Stores::new(stores);
+    if let Err(e) = lnurl::check_addresses(&stores, &conf.server_opts) {
+        eprintln!("Invalid Lightning Addresses: {}", e);
+        process::exit(1);
+    }
+
+    let rates = Rates::from_opts(&conf.server_opts).unwrap_or_else(|e| {
+        eprintln!("Invalid exchange rates: {}", e);
+        process::exit(1)
+    });
 
     // Start server
     let host = conf.host.clone();
     let port = conf.port.clone().to_string();
     let url = format!("{}:{}", host, port);
-    server::run_server(url.as_str(), wallet)
+    if let Err(e) = server::run_server(url.as_str(), stores, rates, conf.server_opts) {
+        eprintln!("Unable to run the server: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Lightning node of the `[clightning]` section, offered next to the bitcoin addresses of the
+/// stores without a lightning rail of their own, opened once and shared by all of them
+struct Companion {
+    opts: Option<ClightningOpts>,
+    wallet: Option<Arc<Mutex<Wallet>>>,
+}
+
+impl Companion {
+    fn wallet(&mut self) -> Option<Arc<Mutex<Wallet>>> {
+        if self.wallet.is_none() {
+            let opts = self.opts.as_ref()?;
+            let wallet: Wallet =
+                Box::new(ClightningWallet::new(opts).unwrap_or_else(|e| exit("clightning", e)));
+            self.wallet = Some(Arc::new(Mutex::new(wallet)));
+        }
+        self.wallet.clone()
+    }
+}
+
+/// Opens the wallets of `platforms`, offering a lightning node next to the addresses of the
+/// bitcoin ones asking for it: the one of the lightning rail or else the `companion` one
+fn open_rails(platforms: &[Platforms], companion: &mut Companion) -> Rails {
+    let mut rails: Vec<Rail> = vec![];
+    for platform in platforms {
+        let wallet: Wallet = match platform {
+            Platforms::Bitcoin(opts) => {
+                Box::new(BTCWallet::new(opts).unwrap_or_else(|e| exit("bitcoin", e)))
+            }
+            Platforms::Liquid(opts) => {
+                Box::new(LiquidWallet::new(opts).unwrap_or_else(|e| exit("liquid", e)))
+            }
+            Platforms::CLightning(opts) => {
+                Box::new(ClightningWallet::new(opts).unwrap_or_else(|e| exit("clightning", e)))
+            }
+        };
+        let rail = Rail::new(wallet).unwrap_or_else(|e| exit(platform.name(), e));
+        // the node of the companion serves this rail, its database can not be opened twice
+        if let Platforms::CLightning(opts) = platform {
+            let same = matches!(&companion.opts, Some(c) if c.data_dir == opts.data_dir);
+            if same && companion.wallet.is_none() {
+                companion.wallet = Some(rail.wallet.clone());
+            }
+        }
+        rails.push(rail);
+    }
+
+    let wants_lightning =
+        |platform: &Platforms| matches!(platform, Platforms::Bitcoin(opts) if opts.lightning);
+    if platforms.iter().any(wants_lightning) {
+        let lightning = match rails.iter().find(|rail| rail.schema == "lightning") {
+            Some(rail) => Some(rail.wallet.clone()),
+            None => companion.wallet(),
+        };
+        let lightning = lightning.unwrap_or_else(|| {
+            exit(
+                "bitcoin",
+                "no lightning node to offer, add a [clightning] section",
+            )
+        });
+        rails = platforms
+            .iter()
+            .zip(rails)
+            .map(|(platform, rail)| match wants_lightning(platform) {
+                true => rail.with_companion(lightning.clone()),
+                false => rail,
+            })
+            .collect();
+    }
+    Rails::new(rails)
+}
+
+/// Stops the server when the `platform` backend can not start, e.g. for a wrong descriptor
+fn exit<T>(platform: &str, e: impl fmt::Display) -> T {
+    eprintln!("Unable to start the {} backend: {}", platform, e);
+    process::exit(1)
 }
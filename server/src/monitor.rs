@@ -0,0 +1,237 @@
+use btctipserver_backend::invoice::{now, Invoice};
+use btctipserver_backend::{Notification, Syncer};
+use log::{debug, error, warn};
+use std::collections::HashSet;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::events::EventBus;
+use crate::invoices;
+use crate::rail::{self, Rail};
+use crate::wallet::Wallet;
+
+/// How often the backend is polled for notifications
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Polls a backend for notifications, forwarding them to the monitors of all the rails using it,
+/// e.g. a lightning node serving its own rail and the companion invoices of the bitcoin one
+pub struct Poller {
+    wallet: Arc<Mutex<Wallet>>,
+    subscribers: Vec<Sender<Notification>>,
+}
+
+impl Poller {
+    /// Returns the pollers of the wallets and companions of `monitors`, one per backend
+    pub fn for_monitors(monitors: &[Monitor]) -> Vec<Poller> {
+        let mut pollers: Vec<Poller> = vec![];
+        for monitor in monitors {
+            let rail = &monitor.rail;
+            for wallet in std::iter::once(&rail.wallet).chain(rail.companion.iter()) {
+                let subscriber = monitor.subscriber.clone();
+                match pollers.iter_mut().find(|p| Arc::ptr_eq(&p.wallet, wallet)) {
+                    Some(poller) => poller.subscribers.push(subscriber),
+                    None => pollers.push(Poller {
+                        wallet: wallet.clone(),
+                        subscribers: vec![subscriber],
+                    }),
+                }
+            }
+        }
+        pollers
+    }
+
+    /// Polls the backend every [`POLL_INTERVAL`] from a background thread, which keeps going
+    /// after a panicking backend
+    pub fn start(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            if panic::catch_unwind(AssertUnwindSafe(|| self.poll())).is_err() {
+                error!("poll panicked");
+            }
+            thread::sleep(POLL_INTERVAL);
+        })
+    }
+
+    fn poll(&self) {
+        let polled = rail::lock_wait(&self.wallet).poll();
+        match polled {
+            Ok(notifications) => {
+                for notification in notifications {
+                    for subscriber in self.subscribers.iter() {
+                        let _ = subscriber.send(notification.clone());
+                    }
+                }
+            }
+            Err(e) => warn!("poll failed: {}", e),
+        }
+    }
+}
+
+/// Keeps the state of a backend and of its pending invoices up to date in background
+pub struct Monitor {
+    rail: Rail,
+    events: EventBus,
+    interval: Duration,
+    /// Addresses watched by the backend
+    watched: HashSet<String>,
+    /// Notifications of the backend and of the companion, sent by their pollers
+    notifications: Receiver<Notification>,
+    subscriber: Sender<Notification>,
+    /// Syncs the backend without locking it, if the backend has one
+    syncer: Option<Box<dyn Syncer>>,
+}
+
+impl Monitor {
+    pub fn new(rail: Rail, events: EventBus, interval: Duration) -> Self {
+        let (subscriber, notifications) = channel();
+        Monitor {
+            rail,
+            events,
+            interval,
+            watched: HashSet::new(),
+            notifications,
+            subscriber,
+            syncer: None,
+        }
+    }
+
+    /// Refreshes the invoices as soon as the backend notifies a payment,
+    /// and syncs every `interval` and on new blocks, from a background thread which keeps
+    /// going after a panicking backend.
+    ///
+    /// The notifications come from the [`Poller`]s of the rail backends.
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let syncer = rail::lock_wait(&self.rail.wallet).syncer();
+            match syncer {
+                Ok(syncer) => self.syncer = syncer,
+                Err(e) => warn!("{} syncs locked: {}", self.rail.schema, e),
+            }
+            let mut synced: Option<Instant> = None;
+            loop {
+                let step = panic::catch_unwind(AssertUnwindSafe(|| self.step(&mut synced)));
+                if step.is_err() {
+                    error!("{} monitor panicked", self.rail.schema);
+                    // synced again on the next step
+                    synced = Some(Instant::now());
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        })
+    }
+
+    /// Watches the new addresses and handles the notifications, syncing when due
+    fn step(&mut self, synced: &mut Option<Instant>) {
+        let watchlist: Vec<String> = rail::lock_wait(&self.rail.watchlist).drain(..).collect();
+        for address in watchlist {
+            self.watch(&address);
+        }
+        let mut new_block = false;
+        for notification in self.receive() {
+            match notification {
+                Notification::NewBlock(_) => new_block = true,
+                Notification::Activity(address) => self.refresh_address(&address),
+            }
+        }
+        let due = match synced {
+            Some(at) => at.elapsed() >= self.interval,
+            None => true,
+        };
+        if new_block || due {
+            if let Err(e) = self.sync() {
+                warn!("{} sync failed: {}", self.rail.schema, e);
+            }
+            *synced = Some(Instant::now());
+        }
+    }
+
+    /// Waits up to [`POLL_INTERVAL`] for notifications, returning all the ones received
+    fn receive(&self) -> Vec<Notification> {
+        let mut notifications = match self.notifications.recv_timeout(POLL_INTERVAL) {
+            Ok(notification) => vec![notification],
+            Err(_) => return vec![],
+        };
+        notifications.extend(self.notifications.try_iter());
+        notifications
+    }
+
+    fn watch(&mut self, address: &str) {
+        if self.watched.contains(address) {
+            return;
+        }
+        match rail::lock_wait(&self.rail.wallet).watch(address) {
+            Ok(_) => {
+                self.watched.insert(address.to_string());
+            }
+            Err(e) => warn!("unable to watch {}: {}", address, e),
+        }
+    }
+
+    fn unwatch(&mut self, address: &str) {
+        if self.watched.remove(address) {
+            if let Err(e) = rail::lock_wait(&self.rail.wallet).unwatch(address) {
+                warn!("unable to unwatch {}: {}", address, e);
+            }
+        }
+    }
+
+    /// Syncs the backend, through its syncer without locking it if it has one, then refreshes
+    /// the pending invoices one at a time, so that the HTTP handlers are never locked out for long.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        match self.syncer.as_mut() {
+            Some(syncer) => syncer.sync()?,
+            None => rail::lock_wait(&self.rail.wallet).sync()?,
+        }
+        let mut pending = HashSet::new();
+        for mut invoice in self.rail.invoices.pending()? {
+            if let Err(e) = self.refresh(&mut invoice) {
+                warn!("invoice {} refresh failed: {}", invoice.id, e);
+            }
+            if invoice.state.is_pending() {
+                pending.insert(invoice.address);
+            }
+        }
+        for address in pending.iter() {
+            self.watch(address);
+        }
+        let done: Vec<String> = self.watched.difference(&pending).cloned().collect();
+        for address in done {
+            self.unwatch(&address);
+        }
+        rail::write(&self.rail.cache).synced_at = Some(now());
+        debug!("{} synced", self.rail.schema);
+        Ok(())
+    }
+
+    /// Refreshes the pending invoices of `address`, on-chain or lightning
+    fn refresh_address(&self, address: &str) {
+        let pending = match self.rail.invoices.pending() {
+            Ok(pending) => pending,
+            Err(e) => {
+                warn!("unable to read the invoices: {}", e);
+                return;
+            }
+        };
+        for mut invoice in pending
+            .into_iter()
+            .filter(|i| i.address == address || i.lightning.as_deref() == Some(address))
+        {
+            if let Err(e) = self.refresh(&mut invoice) {
+                warn!("invoice {} refresh failed: {}", invoice.id, e);
+            }
+        }
+    }
+
+    fn refresh(&self, invoice: &mut Invoice) -> Result<(), Error> {
+        invoices::refresh(&self.rail, &self.events, invoice)?;
+        let balances = rail::lock_wait(&self.rail.wallet)
+            .balance_address(&invoice.address, Option::from(0))?;
+        rail::write(&self.rail.cache)
+            .balances
+            .insert(invoice.address.clone(), balances);
+        Ok(())
+    }
+}
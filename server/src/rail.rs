@@ -0,0 +1,158 @@
+use btctipserver_backend::invoice::{Invoice, InvoiceStore};
+use log::warn;
+use std::collections::HashMap;
+use std::sync::{
+    Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::invoices;
+use crate::wallet::Wallet;
+
+/// Pause between two attempts to lock a busy wallet
+const LOCK_RETRY: Duration = Duration::from_millis(20);
+
+/// Locks `wallet` for a request, failing if it stays busy, e.g. syncing, longer than `timeout`.
+///
+/// A wallet poisoned by a panicking request is still usable, its state lives in the backend.
+pub fn lock(wallet: &Mutex<Wallet>, timeout: Duration) -> Result<MutexGuard<'_, Wallet>, Error> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match wallet.try_lock() {
+            Ok(guard) => return Ok(guard),
+            Err(TryLockError::Poisoned(poisoned)) => return Ok(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => thread::sleep(LOCK_RETRY),
+            Err(TryLockError::WouldBlock) => {
+                warn!("wallet busy for more than {:?}", timeout);
+                return Err(Error::Busy);
+            }
+        }
+    }
+}
+
+/// Locks `mutex` for a background thread, waiting as long as needed.
+///
+/// As for the requests, a mutex poisoned by a panicking request is still usable.
+pub fn lock_wait<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Reads the cache, even if poisoned by a panic while writing it
+pub fn read(cache: &RwLock<Cache>) -> RwLockReadGuard<'_, Cache> {
+    cache.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Writes the cache, even if poisoned by a panic while writing it
+pub fn write(cache: &RwLock<Cache>) -> RwLockWriteGuard<'_, Cache> {
+    cache.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Backend state refreshed by the monitor and read by the HTTP handlers
+#[derive(Debug, Default, Clone)]
+pub struct Cache {
+    pub network: String,
+    /// Received amounts keyed by asset, for every address refreshed by the monitor
+    pub balances: HashMap<String, HashMap<String, String>>,
+    /// Unix time of the last successful sync
+    pub synced_at: Option<u64>,
+}
+
+/// A payment backend shared between the HTTP handlers and its monitor
+#[derive(Clone)]
+pub struct Rail {
+    pub wallet: Arc<Mutex<Wallet>>,
+    pub invoices: InvoiceStore,
+    pub cache: Arc<RwLock<Cache>>,
+    pub schema: String,
+    pub currency: String,
+    /// Addresses of the new invoices, to be watched by the monitor
+    pub watchlist: Arc<Mutex<Vec<String>>>,
+    /// Lightning backend issuing a bolt11 invoice next to every on-chain address
+    pub companion: Option<Arc<Mutex<Wallet>>>,
+}
+
+impl Rail {
+    pub fn new(mut wallet: Wallet) -> Result<Self, Error> {
+        let cache = Cache {
+            network: wallet.network()?,
+            ..Default::default()
+        };
+        Ok(Rail {
+            invoices: wallet.invoices(),
+            schema: wallet.schema().to_string(),
+            currency: wallet.currency().to_string(),
+            wallet: Arc::new(Mutex::new(wallet)),
+            cache: Arc::new(RwLock::new(cache)),
+            watchlist: Arc::new(Mutex::new(vec![])),
+            companion: None,
+        })
+    }
+
+    /// Offers the invoices of `companion` next to the addresses of this rail
+    pub fn with_companion(mut self, companion: Arc<Mutex<Wallet>>) -> Self {
+        self.companion = Some(companion);
+        self
+    }
+
+    /// Asks the monitor to watch `address`
+    pub fn watch(&self, address: &str) {
+        lock_wait(&self.watchlist).push(address.to_string());
+    }
+
+    /// Returns true if the rail pays to addresses derived from its wallet, unlike lightning
+    pub fn is_onchain(&self) -> bool {
+        self.schema != "lightning"
+    }
+
+    pub fn network(&self) -> String {
+        read(&self.cache).network.clone()
+    }
+
+    /// Returns the cached balances of `address`
+    pub fn balances(&self, address: &str) -> HashMap<String, String> {
+        read(&self.cache)
+            .balances
+            .get(address)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// All the rails served, the payer can pick any of them
+#[derive(Clone)]
+pub struct Rails {
+    rails: Vec<Rail>,
+}
+
+impl Rails {
+    /// Serves `rails`, the first one is offered by default
+    pub fn new(rails: Vec<Rail>) -> Self {
+        assert!(!rails.is_empty(), "at least a rail is required");
+        Rails { rails }
+    }
+
+    pub fn default_rail(&self) -> &Rail {
+        &self.rails[0]
+    }
+
+    /// Returns the rail with `schema`
+    pub fn get(&self, schema: &str) -> Option<&Rail> {
+        self.rails.iter().find(|rail| rail.schema == schema)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Rail> {
+        self.rails.iter()
+    }
+
+    /// Looks up an invoice on every rail, returning the rail which issued it
+    pub fn find(&self, id: &str) -> Result<Option<(&Rail, Invoice)>, Error> {
+        for rail in self.rails.iter() {
+            if let Some(invoice) = invoices::find(rail, id)? {
+                return Ok(Some((rail, invoice)));
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,280 @@
+use btctipserver_backend::invoice::FiatPrice;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::config::ServerOpts;
+use crate::error::Error;
+
+/// Source of the exchange rates of the fiat invoices
+pub trait RateProvider: Send + Sync {
+    /// Returns the price of a bitcoin in `currency`, an uppercase ISO 4217 code
+    fn rate(&self, currency: &str) -> Result<f64, Error>;
+}
+
+fn unsupported(currency: &str) -> Error {
+    Error::BadRequest(format!("unsupported currency {}", currency))
+}
+
+/// Reads a rate, as a JSON number or string, from `value`
+fn read_rate(value: Option<&Value>) -> Option<f64> {
+    let rate = match value? {
+        Value::Number(n) => n.as_f64()?,
+        Value::String(s) => s.parse().ok()?,
+        _ => return None,
+    };
+    match rate.is_finite() && rate > 0.0 {
+        true => Some(rate),
+        false => None,
+    }
+}
+
+/// Fixed rates from the config
+pub struct StaticRates {
+    rates: HashMap<String, f64>,
+}
+
+impl StaticRates {
+    /// Parses `<CURRENCY>=<rate>` entries, e.g. `EUR=25000`
+    pub fn parse(entries: &[String]) -> Result<Self, String> {
+        let mut rates = HashMap::new();
+        for entry in entries {
+            let (currency, rate) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("invalid rate {}, expected <CURRENCY>=<rate>", entry))?;
+            let rate = read_rate(Some(&Value::String(rate.trim().to_string())))
+                .ok_or_else(|| format!("invalid rate {}", entry))?;
+            rates.insert(currency.trim().to_uppercase(), rate);
+        }
+        Ok(StaticRates { rates })
+    }
+}
+
+impl RateProvider for StaticRates {
+    fn rate(&self, currency: &str) -> Result<f64, Error> {
+        self.rates
+            .get(currency)
+            .copied()
+            .ok_or_else(|| unsupported(currency))
+    }
+}
+
+/// Rates from a local JSON file, e.g. `{"EUR": 25000, "USD": "27000.5"}`, read on every request
+/// so that it can be updated while the server runs
+pub struct FileRates {
+    path: PathBuf,
+}
+
+impl FileRates {
+    pub fn new(path: &str) -> Self {
+        FileRates {
+            path: PathBuf::from(path),
+        }
+    }
+}
+
+impl RateProvider for FileRates {
+    fn rate(&self, currency: &str) -> Result<f64, Error> {
+        let content = fs::read_to_string(&self.path)
+            .map_err(|e| Error::Rate(format!("{}: {}", self.path.display(), e)))?;
+        let rates: Value = serde_json::from_str(&content)
+            .map_err(|e| Error::Rate(format!("{}: {}", self.path.display(), e)))?;
+        read_rate(rates.get(currency)).ok_or_else(|| unsupported(currency))
+    }
+}
+
+/// Rates fetched from a JSON source, each one reused for `ttl`
+pub struct HttpRates {
+    url: String,
+    pointer: String,
+    ttl: Duration,
+    client: reqwest::blocking::Client,
+    cache: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+/// Replaces the currency placeholders of `template`
+fn expand(template: &str, currency: &str) -> String {
+    template
+        .replace("{currency}", &currency.to_lowercase())
+        .replace("{CURRENCY}", currency)
+}
+
+impl HttpRates {
+    pub fn new(url: &str, pointer: &str, ttl: Duration) -> Self {
+        HttpRates {
+            url: url.to_string(),
+            pointer: pointer.to_string(),
+            ttl,
+            client: reqwest::blocking::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn fetch(&self, currency: &str) -> Result<f64, Error> {
+        let body = self
+            .client
+            .get(expand(&self.url, currency))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .map_err(|e| Error::Rate(e.to_string()))?;
+        let answer: Value = serde_json::from_str(&body).map_err(|e| Error::Rate(e.to_string()))?;
+        read_rate(answer.pointer(&expand(&self.pointer, currency)))
+            .ok_or_else(|| unsupported(currency))
+    }
+}
+
+impl RateProvider for HttpRates {
+    fn rate(&self, currency: &str) -> Result<f64, Error> {
+        let cached = self
+            .cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(currency)
+            .copied();
+        match cached {
+            Some((rate, fetched_at)) if fetched_at.elapsed() < self.ttl => Ok(rate),
+            _ => {
+                let rate = self.fetch(currency)?;
+                let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+                cache.insert(currency.to_string(), (rate, Instant::now()));
+                Ok(rate)
+            }
+        }
+    }
+}
+
+/// The rate provider of the server, if any fiat source is configured
+#[derive(Clone, Default)]
+pub struct Rates {
+    provider: Option<Arc<dyn RateProvider>>,
+}
+
+impl Rates {
+    pub fn new(provider: Arc<dyn RateProvider>) -> Self {
+        Rates {
+            provider: Some(provider),
+        }
+    }
+
+    /// Builds the provider of the first source set among the rates url, the rates file and the
+    /// fixed rates, the fiat invoices are refused without any
+    pub fn from_opts(opts: &ServerOpts) -> Result<Self, String> {
+        if let Some(url) = &opts.rates_url {
+            let ttl = Duration::from_secs(opts.rates_ttl);
+            return Ok(Rates::new(Arc::new(HttpRates::new(
+                url,
+                &opts.rates_pointer,
+                ttl,
+            ))));
+        }
+        if let Some(path) = &opts.rates_file {
+            return Ok(Rates::new(Arc::new(FileRates::new(path))));
+        }
+        if !opts.rates.is_empty() {
+            return Ok(Rates::new(Arc::new(StaticRates::parse(&opts.rates)?)));
+        }
+        Ok(Rates::default())
+    }
+
+    /// Converts `amount` of `currency` at the current rate, which is locked in the returned price
+    pub fn quote(&self, currency: &str, amount: f64) -> Result<FiatPrice, Error> {
+        let provider = self
+            .provider
+            .as_ref()
+            .ok_or_else(|| Error::BadRequest("fiat amounts are not enabled".to_string()))?;
+        let currency = currency.trim().to_uppercase();
+        // also keeps the code from altering the rates url
+        if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(unsupported(&currency));
+        }
+        if !amount.is_finite() || amount <= 0.0 {
+            return Err(Error::BadRequest(format!("invalid price {}", amount)));
+        }
+        let rate = provider.rate(&currency)?;
+        Ok(FiatPrice {
+            currency,
+            amount,
+            rate,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rates::{expand, FileRates, RateProvider, Rates, StaticRates};
+    use btctipserver_backend::invoice::new_id;
+    use std::fs;
+    use std::sync::Arc;
+
+    #[test]
+    fn static_rates() {
+        let rates =
+            StaticRates::parse(&["eur=25000".to_string(), "USD = 27000.5".to_string()]).unwrap();
+        assert_eq!(25000.0, rates.rate("EUR").unwrap());
+        assert_eq!(27000.5, rates.rate("USD").unwrap());
+        assert_eq!(400, rates.rate("CHF").unwrap_err().status());
+
+        assert!(StaticRates::parse(&["EUR".to_string()]).is_err());
+        assert!(StaticRates::parse(&["EUR=-1".to_string()]).is_err());
+        assert!(StaticRates::parse(&["EUR=lots".to_string()]).is_err());
+    }
+
+    #[test]
+    fn file_rates() {
+        // unique to the test run, concurrent runs would overwrite it
+        let name = format!(
+            "btctipserver-rates-{}-{}.json",
+            std::process::id(),
+            new_id()
+        );
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, r#"{"EUR": 25000, "USD": "27000.5", "JPY": null}"#).unwrap();
+        let rates = FileRates::new(path.to_str().unwrap());
+        assert_eq!(25000.0, rates.rate("EUR").unwrap());
+        assert_eq!(27000.5, rates.rate("USD").unwrap());
+        assert_eq!(400, rates.rate("JPY").unwrap_err().status());
+
+        fs::write(&path, "not json").unwrap();
+        assert_eq!(503, rates.rate("EUR").unwrap_err().status());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(503, rates.rate("EUR").unwrap_err().status());
+    }
+
+    #[test]
+    fn quote() {
+        assert_eq!(
+            400,
+            Rates::default().quote("EUR", 1.0).unwrap_err().status()
+        );
+
+        let rates = Rates::new(Arc::new(
+            StaticRates::parse(&["EUR=25000".to_string()]).unwrap(),
+        ));
+        let price = rates.quote(" eur", 2.5).unwrap();
+        assert_eq!("EUR", price.currency);
+        assert_eq!(25000.0, price.rate);
+        assert_eq!(10_000, price.sats());
+        assert!(rates.quote("EUR", 0.0).is_err());
+        assert!(rates.quote("EUR", f64::NAN).is_err());
+        assert!(rates.quote("EU/", 1.0).is_err());
+        assert!(rates.quote("EURO", 1.0).is_err());
+    }
+
+    #[test]
+    fn expand_placeholders() {
+        assert_eq!(
+            "https://rates.example/simple/price?ids=bitcoin&vs_currencies=eur&code=EUR",
+            expand(
+                "https://rates.example/simple/price?ids=bitcoin&vs_currencies={currency}&code={CURRENCY}",
+                "EUR"
+            )
+        );
+    }
+}
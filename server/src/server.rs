@@ -1,98 +1,485 @@
+use btctipserver_backend::sled;
+use log::{debug, error, info, warn};
 use std::convert::TryFrom;
-use std::sync::{Arc, Mutex};
-use tiny_http::{Header, Method, Response, Server};
+use std::fs;
+use std::io::{Cursor, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server};
 use uriparse;
 
-use crate::html::{not_found, Page};
-use crate::{html, wallet};
-use wallet::{gen_err, Error, Wallet};
-
-pub fn run_server(url: &str, wallet: Wallet) {
-    let wallet_mutex = Arc::new(Mutex::new(wallet));
-    let server = Server::http(url).unwrap();
-    for request in server.incoming_requests() {
-        //println!(
-        //    "received request! method: {:?}, url: {:?}, headers: {:?}",
-        //    request.method(),
-        //    request.url(),
-        //    request.headers()
-        //);
-        let mut wallet_lock = wallet_mutex.lock().unwrap();
-        let parsed = uriparse::URIReference::try_from(request.url()).unwrap();
-        let content_type_header = "Content-Type: text/html; charset=utf-8"
-            .parse::<Header>()
-            .unwrap();
-
-        let response = match (request.method(), parsed.path().to_string().as_str()) {
-            (&Method::Get, "/") => {
-                if parsed.query().is_none() {
-                    let html = match redirect(&mut wallet_lock) {
-                        Ok(txt) => txt,
-                        Err(_e) => not_found(),
-                    };
-                    drop(wallet_lock);
-                    Response::from_string(html).with_header(content_type_header)
-                } else {
-                    let html = match page(&mut wallet_lock, parsed.query().unwrap()) {
-                        Ok(txt) => txt,
-                        Err(_e) => not_found(),
-                    };
-                    drop(wallet_lock);
-                    Response::from_string(html).with_header(content_type_header)
-                }
-            }
-            (_, _) => {
-                drop(wallet_lock);
-                Response::from_string(not_found())
-                    .with_status_code(404)
-                    .with_header(content_type_header)
+use crate::config::ServerOpts;
+use crate::error::Error;
+use crate::events::EventBus;
+use crate::form::{self, FORM_PATH};
+use crate::html::{not_found, Form, Page};
+use crate::invoices::InvoiceRequest;
+use crate::lnurl::{self, LNURLP_PATH};
+use crate::monitor::{Monitor, Poller};
+use crate::rail::{self, Rail};
+use crate::rates::Rates;
+use crate::store::{Store, Stores, STORE_PATH};
+use crate::webhook::Webhooks;
+use crate::websocket::Websockets;
+use crate::{api, html, invoices, shutdown, websocket};
+
+const INVOICE_PATH: &str = "/invoice/";
+/// Suffix of the invoice page path for its status websocket
+const WEBSOCKET_SUFFIX: &str = "/ws";
+/// Query parameter picking the rail of a new invoice, e.g. `/?rail=lightning`
+const RAIL_PARAM: &str = "rail";
+/// Largest body read from an API request
+const MAX_BODY: u64 = 64 * 1024;
+/// Longest wait for the websockets to close on shutdown
+const WEBSOCKETS_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opens the server database in the `datadir` of the home directory
+pub fn open_database(datadir: &str) -> Result<sled::Db, Error> {
+    let mut dir = PathBuf::new();
+    let home = dirs_next::home_dir().ok_or_else(|| Error::Io("no home directory".to_string()))?;
+    dir.push(&home);
+    dir.push(datadir);
+    fs::create_dir_all(&dir).map_err(|e| Error::Io(format!("{}: {}", dir.display(), e)))?;
+    dir.push("database.sled");
+    sled::open(dir).map_err(|e| Error::Io(e.to_string()))
+}
+
+fn invoice_not_found() -> Error {
+    Error::NotFound("invoice".to_string())
+}
+
+/// The address of a link is not issued by any store, it is not echoed back to the page
+fn address_not_found() -> Error {
+    Error::NotFound("address".to_string())
+}
+
+fn unknown_rail(schema: &str) -> Error {
+    Error::BadRequest(format!("unknown rail {}", schema))
+}
+
+/// Serves the invoices of all the `stores`, each rail kept up to date by its own monitor,
+/// until a shutdown is requested.
+///
+/// Fails if the server can not listen on `url`, or its database can not be opened.
+pub fn run_server(url: &str, stores: Stores, rates: Rates, opts: ServerOpts) -> Result<(), Error> {
+    let events = EventBus::default();
+    if !opts.webhooks.is_empty() {
+        let database = open_database(&opts.data_dir)?;
+        Webhooks::new(&opts, &database)?.start(&events);
+    }
+    let listener = websocket::listen(url).map_err(|e| Error::Io(format!("{}: {}", url, e)))?;
+    let server =
+        Server::from_listener(listener, None).map_err(|e| Error::Io(format!("{}: {}", url, e)))?;
+    let server = Arc::new(server);
+    let interval = Duration::from_secs(opts.sync_interval);
+    let monitors: Vec<Monitor> = stores
+        .iter()
+        .flat_map(|store| store.rails.iter())
+        .map(|rail| Monitor::new(rail.clone(), events.clone(), interval))
+        .collect();
+    for poller in Poller::for_monitors(&monitors) {
+        poller.start();
+    }
+    for monitor in monitors {
+        monitor.start();
+    }
+
+    shutdown::install();
+    let websockets = Websockets::new(opts.max_websockets);
+    let workers: Vec<_> = (0..opts.workers.max(1))
+        .map(|_| {
+            let (server, stores, rates, opts, events, websockets) = (
+                server.clone(),
+                stores.clone(),
+                rates.clone(),
+                opts.clone(),
+                events.clone(),
+                websockets.clone(),
+            );
+            thread::spawn(move || work(&server, &stores, &rates, &opts, &events, &websockets))
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    if !websockets.wait_closed(WEBSOCKETS_CLOSE_TIMEOUT) {
+        warn!("{} websockets still open", websockets.open());
+    }
+    info!("server stopped");
+    Ok(())
+}
+
+/// Serves the incoming requests, one at a time, until a shutdown is requested
+fn work(
+    server: &Server,
+    stores: &Stores,
+    rates: &Rates,
+    opts: &ServerOpts,
+    events: &EventBus,
+    websockets: &Websockets,
+) {
+    while !shutdown::requested() {
+        let request = match server.recv_timeout(shutdown::POLL) {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("unable to accept requests: {}", e);
+                shutdown::request();
+                break;
             }
         };
-        request.respond(response).unwrap();
+        // a panicking handler drops the request, which is answered with a 500, and the worker
+        // goes on with the next one
+        let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+            handle(request, stores, rates, opts, events, websockets)
+        }));
+        if handled.is_err() {
+            error!("request handler panicked");
+        }
     }
 }
 
-pub fn redirect(wallet: &mut Wallet) -> Result<String, Error> {
-    let address = wallet.last_unused_address()?;
-    let link = format!("/?{}", address);
-    html::redirect(link.as_str()).map_err(|_| gen_err())
-}
+fn handle(
+    mut request: Request,
+    stores: &Stores,
+    rates: &Rates,
+    opts: &ServerOpts,
+    events: &EventBus,
+    websockets: &Websockets,
+) {
+    let url = request.url().to_string();
+    let method = request.method().clone();
+    let parsed = match uriparse::URIReference::try_from(url.as_str()) {
+        Ok(parsed) => parsed,
+        Err(_) => {
+            let response = Response::from_string("bad request").with_status_code(400);
+            let _ = request.respond(response);
+            return;
+        }
+    };
+    let content_type_header = "Content-Type: text/html; charset=utf-8"
+        .parse::<Header>()
+        .unwrap();
 
-pub fn page(wallet: &mut Wallet, uri: &str) -> Result<String, Error> {
-    let mut page = Page {
-        network: wallet.network()?,
-        url: format!("{}", uri),
-        address: format!("{}", uri),
-        ..Default::default()
+    let path = parsed.path().to_string();
+    if method == Method::Get && path.starts_with(INVOICE_PATH) && path.ends_with(WEBSOCKET_SUFFIX) {
+        let id = &path[INVOICE_PATH.len()..path.len() - WEBSOCKET_SUFFIX.len()];
+        websocket::stream(request, stores, events, websockets, id);
+        return;
+    }
+
+    if let Some(store) = form_store(stores, &path) {
+        let result = match method {
+            Method::Get => form_page(store, parsed.query().map(|q| q.as_str())),
+            Method::Post => {
+                let mut body = String::new();
+                let _ = request.as_reader().take(MAX_BODY).read_to_string(&mut body);
+                form_invoice(store, rates, opts, &body)
+            }
+            _ => Err(Error::NotFound("page".to_string())),
+        };
+        if let Err(e) = request.respond(html_response(&url, result)) {
+            debug!("unable to respond to {}: {}", url, e);
+        }
+        return;
+    }
+
+    let response = match (&method, path.as_str()) {
+        (&Method::Get, "/") => {
+            let store = stores.default_store();
+            let result = match parsed.query() {
+                None => redirect(store, None, rates, opts),
+                Some(query) => match rail_param(Some(query.as_str())) {
+                    Some(schema) => redirect(store, Some(&schema), rates, opts),
+                    None => page(stores, opts, query),
+                },
+            };
+            html_response(&url, result)
+        }
+        (&Method::Get, path) if path.starts_with(STORE_PATH) => {
+            let schema = rail_param(parsed.query().map(|q| q.as_str()));
+            let result = stores
+                .get(&path[STORE_PATH.len()..])
+                .ok_or_else(|| Error::NotFound("store".to_string()))
+                .and_then(|store| redirect(store, schema.as_deref(), rates, opts));
+            html_response(&url, result)
+        }
+        (&Method::Get, path) if path.starts_with(INVOICE_PATH) => {
+            let id = &path[INVOICE_PATH.len()..];
+            let result = match rail_param(parsed.query().map(|q| q.as_str())) {
+                Some(schema) => switch_rail(stores, opts, id, &schema),
+                None => invoice_page(stores, opts, id),
+            };
+            html_response(&url, result)
+        }
+        (&Method::Get, path) if path.starts_with(LNURLP_PATH) => {
+            let query = parsed.query().map(|q| q.as_str()).unwrap_or("");
+            let (status, json) = lnurl::handle(stores, rates, opts, path, query);
+            let json_header = "Content-Type: application/json".parse::<Header>().unwrap();
+            // the web wallets fetch it from other origins
+            let cors_header = "Access-Control-Allow-Origin: *".parse::<Header>().unwrap();
+            Response::from_string(json)
+                .with_status_code(status)
+                .with_header(json_header)
+                .with_header(cors_header)
+        }
+        (method, path) if path.starts_with("/api/") => {
+            let mut body = String::new();
+            let _ = request.as_reader().take(MAX_BODY).read_to_string(&mut body);
+            let (status, json) = api::handle(stores, rates, opts, method, path, &body);
+            let json_header = "Content-Type: application/json".parse::<Header>().unwrap();
+            Response::from_string(json)
+                .with_status_code(status)
+                .with_header(json_header)
+        }
+        (_, _) => Response::from_string(not_found())
+            .with_status_code(404)
+            .with_header(content_type_header),
     };
+    if let Err(e) = request.respond(response) {
+        debug!("unable to respond to {}: {}", url, e);
+    }
+}
 
-    if uri.starts_with(wallet.schema()) {
-        println!("{}", wallet.schema());
-        if let Ok(bip21) = btctipserver_bitcoin::bip21::Bip21::parse(uri) {
-            page.address = bip21.address.to_string();
-            if let Some(amount) = bip21.amount {
-                page.amount = Some(amount.as_sat().to_string());
+/// Answers with the page, or with the error page of the failed request to `url`
+fn html_response(url: &str, result: Result<String, Error>) -> Response<Cursor<Vec<u8>>> {
+    let content_type_header = "Content-Type: text/html; charset=utf-8"
+        .parse::<Header>()
+        .unwrap();
+    match result {
+        Ok(html) => Response::from_string(html).with_header(content_type_header),
+        Err(e) => {
+            match e.status() {
+                500..=599 => warn!("{} failed: {}", url, e),
+                _ => debug!("{} failed: {}", url, e),
             }
-            page.label = bip21.label;
-            page.label = bip21.message;
+            Response::from_string(html::error(e.status(), &e.message()))
+                .with_status_code(e.status())
+                .with_header(content_type_header)
         }
     }
-    let mine = wallet.is_my_address(page.address.as_str())?;
-    if !mine {
-        return Ok(format!("Address {} is not mine", page.address));
+}
+
+fn invoice_redirect(id: &str) -> Result<String, Error> {
+    let link = format!("{}{}", INVOICE_PATH, id);
+    html::redirect(link.as_str()).map_err(|e| Error::Render(e.to_string()))
+}
+
+/// Issues an invoice of `store` on the rail with `schema`, or its default one
+pub fn redirect(
+    store: &Store,
+    schema: Option<&str>,
+    rates: &Rates,
+    opts: &ServerOpts,
+) -> Result<String, Error> {
+    let rail = match schema {
+        Some(schema) => store
+            .rails
+            .get(schema)
+            .ok_or_else(|| unknown_rail(schema))?,
+        None => store.rails.default_rail(),
+    };
+    let invoice = invoices::create(rail, store.request(rates)?, opts)?;
+    invoice_redirect(&invoice.id)
+}
+
+/// Returns the store of the amount form at `path`, `/new` for the default one
+fn form_store<'a>(stores: &'a Stores, path: &str) -> Option<&'a Store> {
+    if path == FORM_PATH {
+        return Some(stores.default_store());
     }
-    let results = wallet
-        .balance_address(&page.address, Option::from(0))
-        .map_err(|_| gen_err())?
-        .into_iter()
-        .map(|(k, v)| (k.clone(), v.clone()))
-        .map(|(k, v)| format!("{}: {}", k, v))
-        .collect::<Vec<String>>()
-        .join(", ");
-
-    page.status = match results.is_empty() {
-        true => Some("No tx found yet".to_string()),
-        _ => Some(results),
+    path.strip_prefix(STORE_PATH)
+        .and_then(|path| path.strip_suffix(FORM_PATH))
+        .and_then(|name| stores.get(name))
+}
+
+/// Serves the amount form of `store`, prefilled from the `query` of the link
+pub fn form_page(store: &Store, query: Option<&str>) -> Result<String, Error> {
+    let fields = form::parse(query.unwrap_or(""))?;
+    let defaults = &store.opts;
+    let form = Form {
+        action: store.form_path(),
+        fields: form::FormFields {
+            amount: fields.amount.or(defaults.amount),
+            price: fields.price.or(defaults.price),
+            label: fields.label.or_else(|| defaults.label.clone()),
+            message: fields.message.or_else(|| defaults.message.clone()),
+            rail: fields.rail,
+        },
+        rails: store.rails.iter().map(|rail| rail.schema.clone()).collect(),
+        currency: defaults.currency.clone(),
+        store: store.opts.clone(),
+    };
+    Ok(html::render_form(form))
+}
+
+/// Issues the fixed-amount invoice of the posted form, redirecting to its page
+pub fn form_invoice(
+    store: &Store,
+    rates: &Rates,
+    opts: &ServerOpts,
+    body: &str,
+) -> Result<String, Error> {
+    let fields = form::parse(body)?;
+    let req = InvoiceRequest {
+        label: fields.label,
+        message: fields.message,
+        ..Default::default()
+    };
+    let req = match (fields.price, fields.amount) {
+        (Some(price), _) => req.priced(store.quote(price, rates)?),
+        (None, Some(amount)) => InvoiceRequest {
+            amount: Some(amount),
+            ..req
+        },
+        (None, None) => return Err(Error::BadRequest("missing amount".to_string())),
+    };
+    let rail = match &fields.rail {
+        Some(schema) => store
+            .rails
+            .get(schema)
+            .ok_or_else(|| unknown_rail(schema))?,
+        None => store.rails.default_rail(),
+    };
+    let invoice = invoices::create(rail, store.complete(req, rates)?, opts)?;
+    invoice_redirect(&invoice.id)
+}
+
+pub fn invoice_page(stores: &Stores, opts: &ServerOpts, id: &str) -> Result<String, Error> {
+    let (store, rail, invoice) = stores.find(id)?.ok_or_else(invoice_not_found)?;
+    let url = format!("{}{}", INVOICE_PATH, invoice.id);
+    // a pending invoice can still be paid on any other rail
+    let others = match invoice.state.is_pending() {
+        true => store
+            .rails
+            .iter()
+            .filter(|other| other.schema != rail.schema)
+            .map(|other| other.schema.clone())
+            .collect(),
+        false => vec![],
+    };
+    let page = Page {
+        network: rail.network(),
+        schema: rail.schema.clone(),
+        websocket: Some(format!("{}{}", url, WEBSOCKET_SUFFIX)),
+        url,
+        address: invoice.address.clone(),
+        lightning: invoice.lightning.clone(),
+        amount: invoice.amount,
+        fiat: invoice.fiat.clone(),
+        label: invoice.label.clone(),
+        message: invoice.message.clone(),
+        status: Some(invoices::summary(&invoice)),
+        paid: invoice.state.is_paid(),
+        // pending invoices without an amount may be replaced by one with it
+        form: match invoice.amount.is_none() && invoice.state.is_pending() {
+            true => Some(store.form_path()),
+            false => None,
+        },
+        // as well as paid through LNURL, the wallet picking the amount
+        lnurl: match invoice.amount.is_none() && invoice.state.is_pending() {
+            true => lnurl::url(store, opts).map(|url| lnurl::encode(&url)),
+            false => None,
+        },
+        lightning_address: lnurl::address(store, opts),
+        rails: others,
+        store: store.opts.clone(),
     };
     html::render(page)
 }
+
+/// Issues an invoice like `id` on the rail with `schema`, redirecting to its page
+pub fn switch_rail(
+    stores: &Stores,
+    opts: &ServerOpts,
+    id: &str,
+    schema: &str,
+) -> Result<String, Error> {
+    let (store, _, invoice) = stores.find(id)?.ok_or_else(invoice_not_found)?;
+    let rail = store
+        .rails
+        .get(schema)
+        .ok_or_else(|| unknown_rail(schema))?;
+    // the fiat invoices keep their locked rate
+    let req = InvoiceRequest {
+        amount: invoice.amount,
+        fiat: invoice.fiat,
+        label: invoice.label,
+        message: invoice.message,
+        ..Default::default()
+    };
+    let invoice = invoices::create(rail, req, opts)?;
+    invoice_redirect(&invoice.id)
+}
+
+/// Returns the rail, of any store, which issued `address`, as known from the invoice indexes and
+/// the balances cached by the monitors: the unknown addresses never lock a wallet
+fn owner<'a>(stores: &'a Stores, address: &str) -> Option<&'a Rail> {
+    let rails = || stores.iter().flat_map(|store| store.rails.iter());
+    rails()
+        .find(|rail| matches!(rail.invoices.find_by_address(address), Ok(Some(_))))
+        .or_else(|| rails().find(|rail| rail::read(&rail.cache).balances.contains_key(address)))
+}
+
+/// Serves the links to an address or a payment uri, redirecting to the page of its invoice
+pub fn page(stores: &Stores, opts: &ServerOpts, uri: &str) -> Result<String, Error> {
+    let mut address = uri.to_string();
+    let mut req = InvoiceRequest::default();
+
+    if let Ok(bip21) = btctipserver_bitcoin::bip21::Bip21::parse(uri) {
+        address = bip21.address.to_string();
+        req.amount = bip21.amount.map(|amount| amount.as_sat());
+        req.label = bip21.label;
+        req.message = bip21.message;
+    }
+    // the address may belong to the rail of any store
+    let rail = match owner(stores, &address) {
+        Some(rail) => rail,
+        None => return Err(address_not_found()),
+    };
+    let invoice = match rail.invoices.find_by_address(&address)? {
+        Some(invoice) => invoice,
+        None => {
+            let timeout = Duration::from_secs(opts.request_timeout);
+            let mine = rail::lock(&rail.wallet, timeout)?.is_my_address(&address)?;
+            if !mine {
+                return Err(address_not_found());
+            }
+            invoices::register(rail, address, req, opts)?
+        }
+    };
+    invoice_redirect(&invoice.id)
+}
+
+/// Returns the rail picked by the `rail` parameter of `query`, if any
+fn rail_param(query: Option<&str>) -> Option<String> {
+    url::form_urlencoded::parse(query?.as_bytes())
+        .find(|(key, _)| key == RAIL_PARAM)
+        .map(|(_, value)| value.into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::rail_param;
+
+    #[test]
+    fn rail_query() {
+        let rail = |query| rail_param(Some(query));
+        assert_eq!(Some("lightning".to_string()), rail("rail=lightning"));
+        assert_eq!(Some("lightning".to_string()), rail("x=1&rail=lightning"));
+        assert_eq!(Some("lightning".to_string()), rail("rail=lightning&x=1"));
+        assert_eq!(
+            Some("liquid network".to_string()),
+            rail("rail=liquid%20network")
+        );
+        assert_eq!(None, rail("2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK"));
+        assert_eq!(
+            None,
+            rail("bitcoin:2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK?amount=1&label=x")
+        );
+        assert_eq!(None, rail_param(None));
+    }
+}
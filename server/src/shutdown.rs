@@ -0,0 +1,34 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Interval the idle threads check for a shutdown request
+pub const POLL: Duration = Duration::from_millis(500);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks the server to stop on SIGTERM and SIGINT, letting the workers finish their requests
+#[cfg(unix)]
+pub fn install() {
+    extern "C" fn handler(_signal: libc::c_int) {
+        // only async-signal-safe work here, the workers poll the flag
+        REQUESTED.store(true, Ordering::SeqCst);
+    }
+    let handler = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
+
+/// Returns true once a shutdown has been requested
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Requests a shutdown, as the signals do
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
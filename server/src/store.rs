@@ -0,0 +1,105 @@
+use btctipserver_backend::invoice::{FiatPrice, Invoice};
+
+use crate::config::StoreOpts;
+use crate::error::Error;
+use crate::form::FORM_PATH;
+use crate::invoices::InvoiceRequest;
+use crate::rail::{Rail, Rails};
+use crate::rates::Rates;
+
+/// Path of the named store pages, e.g. `/s/alice`
+pub const STORE_PATH: &str = "/s/";
+
+/// A tip page with its own rails, defaults and branding
+#[derive(Clone)]
+pub struct Store {
+    pub name: String,
+    pub opts: StoreOpts,
+    pub rails: Rails,
+}
+
+impl Store {
+    pub fn new(name: &str, opts: StoreOpts, rails: Rails) -> Self {
+        Store {
+            name: name.to_string(),
+            opts,
+            rails,
+        }
+    }
+
+    /// Returns the request of the invoices issued by the store page
+    pub fn request(&self, rates: &Rates) -> Result<InvoiceRequest, Error> {
+        self.complete(InvoiceRequest::default(), rates)
+    }
+
+    /// Path of the amount form of the store
+    pub fn form_path(&self) -> String {
+        match self.name.is_empty() {
+            true => FORM_PATH.to_string(),
+            false => format!("{}{}{}", STORE_PATH, self.name, FORM_PATH),
+        }
+    }
+
+    /// Fills the missing fields of `req` with the defaults of the store, converting its price
+    /// at the current rate
+    pub fn complete(&self, req: InvoiceRequest, rates: &Rates) -> Result<InvoiceRequest, Error> {
+        let req = InvoiceRequest {
+            label: req.label.or_else(|| self.opts.label.clone()),
+            message: req.message.or_else(|| self.opts.message.clone()),
+            confirmations: req.confirmations.or(self.opts.confirmations),
+            ..req
+        };
+        match (req.amount, self.opts.price) {
+            (Some(_), _) => Ok(req),
+            (None, Some(price)) => Ok(req.priced(self.quote(price, rates)?)),
+            (None, None) => Ok(InvoiceRequest {
+                amount: self.opts.amount,
+                ..req
+            }),
+        }
+    }
+
+    /// Converts `price`, in the store currency, at the current rate
+    pub fn quote(&self, price: f64, rates: &Rates) -> Result<FiatPrice, Error> {
+        let currency =
+            self.opts.currency.as_deref().ok_or_else(|| {
+                Error::BadRequest(format!("the store {} has no currency", self.name))
+            })?;
+        rates.quote(currency, price)
+    }
+}
+
+/// All the stores served, the first one at `/` and the others at `/s/{name}`
+#[derive(Clone)]
+pub struct Stores {
+    stores: Vec<Store>,
+}
+
+impl Stores {
+    pub fn new(stores: Vec<Store>) -> Self {
+        assert!(!stores.is_empty(), "at least a store is required");
+        Stores { stores }
+    }
+
+    pub fn default_store(&self) -> &Store {
+        &self.stores[0]
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Store> {
+        self.stores.iter().find(|store| store.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Store> {
+        self.stores.iter()
+    }
+
+    /// Looks up an invoice in every store, returning the store and the rail which issued it
+    pub fn find(&self, id: &str) -> Result<Option<(&Store, &Rail, Invoice)>, Error> {
+        for store in self.stores.iter() {
+            if let Some((rail, invoice)) = store.rails.find(id)? {
+                return Ok(Some((store, rail, invoice)));
+            }
+        }
+        Ok(None)
+    }
+}
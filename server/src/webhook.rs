@@ -0,0 +1,380 @@
+use btctipserver_backend::invoice::now;
+use btctipserver_backend::sled;
+use btctipserver_bitcoin::bdk::bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::ServerOpts;
+use crate::error::Error;
+use crate::events::{EventBus, PaymentEvent};
+
+/// Header carrying `sha256=<hex hmac of the signed payload>`, keyed with the webhook secret
+pub const SIGNATURE_HEADER: &str = "X-BTCTipServer-Signature";
+/// Header carrying the unix time of the delivery, signed with the body against replays
+pub const TIMESTAMP_HEADER: &str = "X-BTCTipServer-Timestamp";
+/// Name of the sled tree keeping the delivery log
+const LOG: &str = "webhooks";
+/// Name of the sled tree keeping the pending retries, by url and event id
+const RETRIES: &str = "webhook_retries";
+
+/// Attempt to deliver an event to a webhook, as stored in the delivery log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub event_id: String,
+    pub url: String,
+    pub attempt: u32,
+    /// HTTP status code returned by the webhook
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub timestamp: u64,
+}
+
+impl Delivery {
+    pub fn is_success(&self) -> bool {
+        matches!(self.status, Some(status) if (200..300).contains(&status))
+    }
+}
+
+/// Returns the hex encoded HMAC-SHA256 of `body` keyed with `secret`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body);
+    hmac::Hmac::<sha256::Hash>::from_engine(engine).to_string()
+}
+
+/// Returns the payload signed for the delivery of `body` at `timestamp`, `<timestamp>.<body>`
+pub fn signed_payload(timestamp: u64, body: &[u8]) -> Vec<u8> {
+    let mut payload = format!("{}.", timestamp).into_bytes();
+    payload.extend_from_slice(body);
+    payload
+}
+
+/// Delay before the `attempt`-th retry
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(2u64.pow(attempt.min(6)))
+}
+
+/// Notifies the configured urls of every payment event
+#[derive(Clone)]
+pub struct Webhooks {
+    urls: Vec<String>,
+    secret: Option<String>,
+    retries: u32,
+    log: sled::Tree,
+    pending: sled::Tree,
+    client: reqwest::blocking::Client,
+}
+
+/// Event waiting for its next delivery attempt to a url, persisted until delivered or given up
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Retry {
+    event: PaymentEvent,
+    attempt: u32,
+    /// Unix time of the attempt
+    at: u64,
+}
+
+/// Key of the pending retry of `event_id` to `url`
+fn retry_key(url: &str, event_id: &str) -> String {
+    format!("{} {}", url, event_id)
+}
+
+impl Webhooks {
+    /// Fails if the trees of `database` can not be opened, or the http client built
+    pub fn new(opts: &ServerOpts, database: &sled::Db) -> Result<Self, Error> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|e| Error::Io(e.to_string()))?;
+        Ok(Webhooks {
+            urls: opts.webhooks.clone(),
+            secret: opts.webhook_secret.clone(),
+            retries: opts.webhook_retries,
+            log: database
+                .open_tree(LOG)
+                .map_err(|e| Error::Io(e.to_string()))?,
+            pending: database
+                .open_tree(RETRIES)
+                .map_err(|e| Error::Io(e.to_string()))?,
+            client,
+        })
+    }
+
+    /// Delivers the events published on `events` from a background thread per url, so that
+    /// a slow url never delays the others
+    pub fn start(self, events: &EventBus) -> Vec<thread::JoinHandle<()>> {
+        self.urls
+            .iter()
+            .map(|url| {
+                let receiver = events.subscribe();
+                let webhooks = self.clone();
+                let url = url.clone();
+                thread::spawn(move || webhooks.run(&url, receiver))
+            })
+            .collect()
+    }
+
+    /// Delivers the events of `receiver` to `url`, scheduling the retries with exponential
+    /// backoff while the next events are delivered, starting with the retries left pending
+    /// by the last run
+    fn run(&self, url: &str, receiver: Receiver<PaymentEvent>) {
+        let mut retries = self.pending(url);
+        loop {
+            let received = match retries.iter().map(|retry| retry.at).min() {
+                Some(at) => receiver.recv_timeout(Duration::from_secs(at.saturating_sub(now()))),
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(event) => retries.extend(self.attempt(url, event, 0)),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            let now = now();
+            let (due, later): (Vec<Retry>, Vec<Retry>) =
+                retries.into_iter().partition(|retry| retry.at <= now);
+            retries = later;
+            for retry in due {
+                retries.extend(self.attempt(url, retry.event, retry.attempt));
+            }
+        }
+    }
+
+    /// Sends `event` to `url`, returning the next attempt if it failed and can be retried
+    fn attempt(&self, url: &str, event: PaymentEvent, attempt: u32) -> Option<Retry> {
+        let key = retry_key(url, &event.id);
+        let retry = self.send(url, event, attempt);
+        let res = match &retry {
+            Some(retry) => serde_json::to_vec(retry)
+                .map_err(|e| e.to_string())
+                .and_then(|value| self.pending.insert(key, value).map_err(|e| e.to_string())),
+            None => self.pending.remove(key).map_err(|e| e.to_string()),
+        };
+        if let Err(e) = res.and_then(|_| self.pending.flush().map_err(|e| e.to_string())) {
+            warn!("unable to store the webhook retry: {}", e);
+        }
+        retry
+    }
+
+    fn send(&self, url: &str, event: PaymentEvent, attempt: u32) -> Option<Retry> {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("unable to serialize event {}: {}", event.id, e);
+                return None;
+            }
+        };
+        let delivery = self.deliver(url, &event, &body, attempt);
+        self.record(&delivery);
+        if delivery.is_success() {
+            info!("event {} delivered to {}", event.id, url);
+            return None;
+        }
+        warn!(
+            "event {} not delivered to {}: {:?} {:?}",
+            event.id, url, delivery.status, delivery.error
+        );
+        if attempt >= self.retries {
+            return None;
+        }
+        Some(Retry {
+            event,
+            attempt: attempt + 1,
+            at: now() + backoff(attempt + 1).as_secs(),
+        })
+    }
+
+    /// Returns the retries to `url` left pending by the last run
+    fn pending(&self, url: &str) -> Vec<Retry> {
+        self.pending
+            .scan_prefix(retry_key(url, ""))
+            .values()
+            .filter_map(|value| value.ok())
+            .filter_map(|value| serde_json::from_slice(&value).ok())
+            .collect()
+    }
+
+    fn deliver(&self, url: &str, event: &PaymentEvent, body: &[u8], attempt: u32) -> Delivery {
+        let timestamp = now();
+        let mut request = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .body(body.to_vec());
+        if let Some(secret) = &self.secret {
+            let signature = sign(secret, &signed_payload(timestamp, body));
+            request = request.header(SIGNATURE_HEADER, format!("sha256={}", signature));
+        }
+        let (status, error) = match request.send() {
+            Ok(res) => (Some(res.status().as_u16()), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        Delivery {
+            event_id: event.id.clone(),
+            url: url.to_string(),
+            attempt,
+            status,
+            error,
+            timestamp,
+        }
+    }
+
+    fn record(&self, delivery: &Delivery) {
+        let key = format!(
+            "{}-{}-{}-{}",
+            delivery.timestamp, delivery.event_id, delivery.url, delivery.attempt
+        );
+        let res = serde_json::to_vec(delivery)
+            .map_err(|e| e.to_string())
+            .and_then(|value| self.log.insert(key, value).map_err(|e| e.to_string()))
+            .and_then(|_| self.log.flush().map_err(|e| e.to_string()));
+        if let Err(e) = res {
+            warn!("unable to store the webhook delivery: {}", e);
+        }
+    }
+
+    /// Returns the delivery log, oldest first
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        self.log
+            .iter()
+            .values()
+            .filter_map(|value| value.ok())
+            .filter_map(|value| serde_json::from_slice(&value).ok())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sign, signed_payload, Webhooks, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+    use crate::events::PaymentEvent;
+    use btctipserver_backend::invoice::{now, InvoiceState};
+    use btctipserver_backend::sled;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    fn event() -> PaymentEvent {
+        PaymentEvent {
+            id: "abc-1".to_string(),
+            invoice_id: "abc".to_string(),
+            address: "2NDxuABdg2uqk9MouV6f53acAwaY2GwKVHK".to_string(),
+            amount: 1000,
+            txid: Some("00".repeat(32)),
+            payment_hash: None,
+            confirmations: Some(1),
+            state: InvoiceState::Paid,
+            timestamp: 1,
+        }
+    }
+
+    #[test]
+    fn signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            sign("Jefe", b"what do ya want for nothing?")
+        );
+    }
+
+    #[test]
+    fn notify() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut headers = vec![];
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                headers.push(line.trim().to_lowercase());
+            }
+            let length = headers
+                .iter()
+                .find_map(|h| h.strip_prefix("content-length:"))
+                .map(|l| l.trim().parse::<usize>().unwrap())
+                .unwrap();
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            sender.send((headers, body)).unwrap();
+        });
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let webhooks = Webhooks {
+            urls: vec![url.clone()],
+            secret: Some("secret".to_string()),
+            retries: 0,
+            log: db.open_tree("webhooks").unwrap(),
+            pending: db.open_tree("webhook_retries").unwrap(),
+            client: reqwest::blocking::Client::new(),
+        };
+        assert!(webhooks.attempt(&url, event(), 0).is_none());
+
+        let (headers, body) = receiver.recv().unwrap();
+        let prefix = format!("{}:", TIMESTAMP_HEADER.to_lowercase());
+        let timestamp = headers
+            .iter()
+            .find_map(|h| h.strip_prefix(&prefix))
+            .map(|t| t.trim().parse::<u64>().unwrap())
+            .unwrap();
+        assert!(timestamp <= now());
+        // the signature covers the timestamp
+        let signature = format!(
+            "{}: sha256={}",
+            SIGNATURE_HEADER.to_lowercase(),
+            sign("secret", &signed_payload(timestamp, &body))
+        );
+        assert!(headers.contains(&signature));
+        assert_eq!(
+            event(),
+            serde_json::from_slice::<PaymentEvent>(&body).unwrap()
+        );
+
+        let deliveries = webhooks.deliveries();
+        assert_eq!(1, deliveries.len());
+        assert_eq!(url, deliveries[0].url);
+        assert!(deliveries[0].is_success());
+    }
+
+    #[test]
+    fn retry() {
+        // nothing listens on the port once the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        drop(listener);
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let webhooks = Webhooks {
+            urls: vec![url.clone()],
+            secret: None,
+            retries: 1,
+            log: db.open_tree("webhooks").unwrap(),
+            pending: db.open_tree("webhook_retries").unwrap(),
+            client: reqwest::blocking::Client::new(),
+        };
+        let retry = webhooks.attempt(&url, event(), 0).unwrap();
+        assert_eq!(1, retry.attempt);
+        assert!(retry.at > now());
+        // reloaded by the next run
+        assert_eq!(vec![retry.clone()], webhooks.pending(&url));
+        assert!(webhooks.pending("http://127.0.0.1:1/hook").is_empty());
+        assert!(webhooks.attempt(&url, retry.event, retry.attempt).is_none());
+        assert!(webhooks.pending(&url).is_empty());
+
+        let deliveries = webhooks.deliveries();
+        assert_eq!(2, deliveries.len());
+        assert!(deliveries.iter().all(|delivery| !delivery.is_success()));
+    }
+}
@@ -0,0 +1,480 @@
+use btctipserver_backend::invoice::{Invoice, InvoiceState, InvoiceStore};
+use btctipserver_bitcoin::bdk::bitcoin::hashes::{sha1, Hash};
+use log::debug;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, ReadWrite, Request, Response, StatusCode};
+
+use crate::events::{EventBus, PaymentEvent};
+use crate::invoices;
+use crate::shutdown;
+use crate::store::Stores;
+
+/// Magic string of the opening handshake (RFC 6455)
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+/// Version of the protocol spoken by the server (RFC 6455)
+const VERSION: &str = "13";
+/// Idle clients are pinged to detect closed connections
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Longest wait for a frame of a client, its pong included, before dropping the connection
+pub const READ_TIMEOUT: Duration = PING_INTERVAL;
+/// Longest payload read from a client, the pages only send control frames
+const MAX_PAYLOAD: u64 = 4096;
+
+/// Counts the open websockets, refusing the new ones above a limit
+#[derive(Clone)]
+pub struct Websockets {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// An open websocket, released when dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Websockets {
+    pub fn new(max: usize) -> Self {
+        Websockets {
+            open: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Returns the number of open websockets
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /// Reserves a websocket, if less than the limit are open
+    fn acquire(&self) -> Option<Slot> {
+        self.open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                Some(open + 1).filter(|open| *open <= self.max)
+            })
+            .ok()
+            .map(|_| Slot(self.open.clone()))
+    }
+
+    /// Waits up to `timeout` for all the websockets to close, returns false if some are still open
+    pub fn wait_closed(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.open() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        true
+    }
+}
+
+/// Status of an invoice pushed to the payment page
+#[derive(Debug, Serialize)]
+pub struct StatusMessage {
+    pub id: String,
+    pub state: InvoiceState,
+    /// Status line shown on the page
+    pub summary: String,
+    pub received: u64,
+    pub confirmations: Option<u32>,
+    /// True once the invoice has been paid
+    pub paid: bool,
+    /// True if the invoice will not change anymore
+    pub done: bool,
+}
+
+impl From<&Invoice> for StatusMessage {
+    fn from(invoice: &Invoice) -> Self {
+        StatusMessage {
+            id: invoice.id.clone(),
+            state: invoice.state,
+            summary: invoices::summary(invoice),
+            received: invoice.received,
+            confirmations: invoice.confirmations,
+            paid: invoice.state.is_paid(),
+            done: !invoice.state.is_pending(),
+        }
+    }
+}
+
+/// Returns the `Sec-WebSocket-Accept` value for the client `key`
+pub fn accept_key(key: &str) -> String {
+    let hash = sha1::Hash::hash(format!("{}{}", key, GUID).as_bytes());
+    base64::encode(&hash[..])
+}
+
+/// Encodes `payload` in a single unmasked frame, as sent by servers
+pub fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Frame sent by a client
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+fn invalid(e: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Reads a frame sent by a client, unmasking its payload
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Frame> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head)?;
+    // clients always mask their frames
+    if head[1] & 0x80 == 0 {
+        return Err(invalid("unmasked frame"));
+    }
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if len > MAX_PAYLOAD {
+        return Err(invalid("frame too long"));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        opcode: head[0] & 0x0f,
+        payload,
+    })
+}
+
+/// Checks the headers of the opening handshake, returning the client key, or the status code
+/// refusing it with the headers to send back
+fn check_handshake(headers: &[Header]) -> Result<String, (u16, Vec<Header>)> {
+    let header = |name: &'static str| {
+        headers
+            .iter()
+            .find(|header| header.field.equiv(name))
+            .map(|header| header.value.as_str().to_string())
+    };
+    let has_token = |name: &'static str, token: &str| {
+        matches!(header(name), Some(value)
+            if value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err((400, vec![]));
+    }
+    if header("Sec-WebSocket-Version").as_deref() != Some(VERSION) {
+        let version = Header::from_bytes(&b"Sec-WebSocket-Version"[..], VERSION).unwrap();
+        return Err((426, vec![version]));
+    }
+    header("Sec-WebSocket-Key").ok_or((400, vec![]))
+}
+
+fn send(socket: &mut Box<dyn ReadWrite + Send>, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    socket.write_all(&frame(opcode, payload))?;
+    socket.flush()
+}
+
+/// Binds the server to `addr`, with the read timeout of the websockets.
+///
+/// The upgraded connections hide their socket, so the timeout is set on the listener instead,
+/// whose accepted sockets inherit it: it also bounds the reads of the plain requests.
+pub fn listen(addr: &str) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr)?;
+    let timeout = libc::timeval {
+        tv_sec: READ_TIMEOUT.as_secs() as libc::time_t,
+        tv_usec: 0,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            listener.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(listener)
+}
+
+/// Upgrades `request` to a WebSocket pushing the status of the invoice `id`,
+/// from a thread of its own, until the invoice is done or the server stops.
+///
+/// The connections above the limit of `websockets` are refused.
+pub fn stream(
+    request: Request,
+    stores: &Stores,
+    events: &EventBus,
+    websockets: &Websockets,
+    id: &str,
+) {
+    let key = match check_handshake(request.headers()) {
+        Ok(key) => key,
+        Err((status, headers)) => {
+            let mut response =
+                Response::from_string("expected a websocket").with_status_code(status);
+            for header in headers {
+                response.add_header(header);
+            }
+            let _ = request.respond(response);
+            return;
+        }
+    };
+    let (_, rail, invoice) = match stores.find(id) {
+        Ok(Some(found)) => found,
+        _ => {
+            let response = Response::from_string("invoice not found").with_status_code(404);
+            let _ = request.respond(response);
+            return;
+        }
+    };
+    let slot = match websockets.acquire() {
+        Some(slot) => slot,
+        None => {
+            let response = Response::from_string("too many websockets").with_status_code(503);
+            let _ = request.respond(response);
+            return;
+        }
+    };
+
+    // subscribe before the upgrade, not to miss any change
+    let receiver = events.subscribe();
+    let accept =
+        Header::from_bytes(&b"Sec-WebSocket-Accept"[..], accept_key(&key).as_bytes()).unwrap();
+    let response = Response::empty(StatusCode(101)).with_header(accept);
+    let socket = request.upgrade("websocket", response);
+    let invoices = rail.invoices.clone();
+    thread::spawn(move || {
+        let id = invoice.id.clone();
+        if let Err(e) = serve(socket, receiver, invoices, invoice) {
+            debug!("websocket of invoice {} closed: {}", id, e);
+        }
+        drop(slot);
+    });
+}
+
+fn serve(
+    mut socket: Box<dyn ReadWrite + Send>,
+    receiver: Receiver<PaymentEvent>,
+    invoices: InvoiceStore,
+    mut invoice: Invoice,
+) -> io::Result<()> {
+    loop {
+        let message = serde_json::to_vec(&StatusMessage::from(&invoice))?;
+        send(&mut socket, OPCODE_TEXT, &message)?;
+        if !invoice.state.is_pending() {
+            return send(&mut socket, OPCODE_CLOSE, &[]);
+        }
+        wait(&mut socket, &receiver, &invoice.id)?;
+        if let Ok(Some(updated)) = invoices.get(&invoice.id) {
+            invoice = updated;
+        }
+    }
+}
+
+/// Pings the client, then reads its frames until the pong, answering its pings and its close.
+/// Fails, dropping the connection, if the client sends nothing for `READ_TIMEOUT`.
+///
+/// The upgraded connection can not be read and written from two threads, so the frames the
+/// client sent meanwhile are read at every ping.
+fn ping(socket: &mut Box<dyn ReadWrite + Send>) -> io::Result<()> {
+    send(socket, OPCODE_PING, &[])?;
+    loop {
+        let frame = read_frame(socket)?;
+        match frame.opcode {
+            OPCODE_PONG => return Ok(()),
+            OPCODE_PING => send(socket, OPCODE_PONG, &frame.payload)?,
+            OPCODE_CLOSE => {
+                // echo the status code of the client
+                let code = &frame.payload[..frame.payload.len().min(2)];
+                send(socket, OPCODE_CLOSE, code)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "closed by the client",
+                ));
+            }
+            // the page sends no data
+            _ => {}
+        }
+    }
+}
+
+/// Waits for the next event of the invoice `id`, pinging the client meanwhile, and closes the
+/// websocket if the server stops
+fn wait(
+    socket: &mut Box<dyn ReadWrite + Send>,
+    receiver: &Receiver<PaymentEvent>,
+    id: &str,
+) -> io::Result<()> {
+    let mut pinged = Instant::now();
+    loop {
+        if shutdown::requested() {
+            // going away (RFC 6455)
+            send(socket, OPCODE_CLOSE, &1001u16.to_be_bytes())?;
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "server stopping",
+            ));
+        }
+        match receiver.recv_timeout(shutdown::POLL) {
+            Ok(event) if event.invoice_id == id => return Ok(()),
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) if pinged.elapsed() >= PING_INTERVAL => {
+                ping(socket)?;
+                pinged = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "no more events"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::websocket::{
+        accept_key, check_handshake, frame, listen, read_frame, Frame, Websockets, OPCODE_CLOSE,
+        OPCODE_PING, OPCODE_TEXT, READ_TIMEOUT,
+    };
+    use std::net::TcpStream;
+    use std::time::Duration;
+    use tiny_http::Header;
+
+    fn headers(version: &str) -> Vec<Header> {
+        vec![
+            "Upgrade: websocket".parse().unwrap(),
+            "Connection: keep-alive, Upgrade".parse().unwrap(),
+            format!("Sec-WebSocket-Version: {}", version)
+                .parse()
+                .unwrap(),
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ=="
+                .parse()
+                .unwrap(),
+        ]
+    }
+
+    #[test]
+    fn handshake() {
+        // sample handshake of RFC 6455
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept_key("dGhlIHNhbXBsZSBub25jZQ==")
+        );
+        assert_eq!(
+            Some("dGhlIHNhbXBsZSBub25jZQ==".to_string()),
+            check_handshake(&headers("13")).ok()
+        );
+        let (status, headers) = check_handshake(&headers("8")).unwrap_err();
+        assert_eq!(426, status);
+        assert_eq!("13", headers[0].value.as_str());
+        let mut plain = self::headers("13");
+        plain.remove(0);
+        assert_eq!(400, check_handshake(&plain).unwrap_err().0);
+    }
+
+    #[test]
+    fn client_frames() {
+        // masked "Hello" of RFC 6455
+        let masked = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert_eq!(
+            Frame {
+                opcode: OPCODE_TEXT,
+                payload: b"Hello".to_vec()
+            },
+            read_frame(&mut &masked[..]).unwrap()
+        );
+        let ping = [0x89, 0x80, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(OPCODE_PING, read_frame(&mut &ping[..]).unwrap().opcode);
+        // unmasked, too long or truncated
+        assert!(read_frame(&mut &frame(OPCODE_TEXT, b"Hello")[..]).is_err());
+        let long = [0x81, 0xff, 0, 0, 0, 0, 0, 1, 0, 0];
+        assert!(read_frame(&mut &long[..]).is_err());
+        assert!(read_frame(&mut &masked[..8]).is_err());
+    }
+
+    #[test]
+    fn frames() {
+        assert_eq!(vec![0x88, 0x00], frame(OPCODE_CLOSE, &[]));
+        assert_eq!(
+            vec![0x81, 0x05, b'H', b'e', b'l', b'l', b'o'],
+            frame(OPCODE_TEXT, b"Hello")
+        );
+        let payload = vec![0u8; 256];
+        assert_eq!(
+            vec![0x81, 126, 0x01, 0x00],
+            frame(OPCODE_TEXT, &payload)[..4]
+        );
+        let payload = vec![0u8; 65536];
+        assert_eq!(
+            vec![0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0],
+            frame(OPCODE_TEXT, &payload)[..10]
+        );
+    }
+
+    #[test]
+    fn limit() {
+        let websockets = Websockets::new(2);
+        let first = websockets.acquire().unwrap();
+        let second = websockets.acquire().unwrap();
+        assert!(websockets.acquire().is_none());
+        assert_eq!(2, websockets.open());
+        drop(first);
+        let third = websockets.acquire().unwrap();
+        assert!(!websockets.wait_closed(Duration::from_millis(10)));
+        drop((second, third));
+        assert!(websockets.wait_closed(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn accepted_read_timeout() {
+        let listener = listen("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        assert_eq!(Some(READ_TIMEOUT), accepted.read_timeout().unwrap());
+    }
+}